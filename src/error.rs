@@ -1,44 +1,80 @@
-use jsonrpc_core;
+use jsonrpc_core::{self, ErrorCode};
+use bigint::{Address, Gas, U256};
 use secp256k1;
+use serde_json::Value;
 use sputnikvm::errors::PreExecutionError;
 use rlp::DecoderError;
-use hexutil::ParseHexError;
+use hexutil::{ParseHexError, to_hex};
 use std::num::ParseIntError;
+use std::io;
 
 #[derive(Debug)]
 pub enum Error {
-    InvalidParams,
-    HexError,
-    IntError,
+    /// What is wrong with the parameters.
+    InvalidParams(String),
+    /// The offending value, or the parse error.
+    HexError(String),
+    IntError(String),
     UnsupportedTrieQuery,
     ECDSAError,
     NotFound,
-    RlpError,
-    CallError,
+    UnknownAccount(Address),
+    RlpError(String),
+    /// The `PreExecutionError` that made the transaction invalid.
+    CallError(String),
+    /// Execution reverted, with the revert output.
+    Reverted(Vec<u8>),
+    /// Execution failed other than by reverting, with the reason.
+    ExecutionFailed(String),
+    /// The transaction fails even with the given gas limit.
+    GasRequiredExceedsAllowance(Gas),
+    ExceedsBlockGasLimit,
+    NonceTooLow,
+    ReplacementUnderpriced,
     UnknownSourceMapJump,
+    IOError(String),
+    InvalidBlockNumber,
+    ParentHashMismatch,
+    /// The number of a block that differs from the local one.
+    BlockHashMismatch(U256),
+    StateRootMismatch,
+    ReceiptsRootMismatch,
+    GasUsedMismatch,
+    UpstreamError,
+    AccountLocked,
+    InvalidPassword,
+    InvalidChainId,
+    /// A failure of the node itself rather than of the request.
+    InternalError(String),
 }
 
 impl From<PreExecutionError> for Error {
     fn from(val: PreExecutionError) -> Error {
-        Error::CallError
+        Error::CallError(format!("{:?}", val))
     }
 }
 
 impl From<DecoderError> for Error {
     fn from(val: DecoderError) -> Error {
-        Error::RlpError
+        Error::RlpError(format!("{:?}", val))
     }
 }
 
 impl From<ParseHexError> for Error {
     fn from(val: ParseHexError) -> Error {
-        Error::HexError
+        Error::HexError(format!("{:?}", val))
     }
 }
 
 impl From<ParseIntError> for Error {
     fn from(val: ParseIntError) -> Error {
-        Error::IntError
+        Error::IntError(format!("{}", val))
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(val: io::Error) -> Error {
+        Error::IOError(format!("{}", val))
     }
 }
 
@@ -48,8 +84,124 @@ impl From<secp256k1::Error> for Error {
     }
 }
 
+/// Selector of Solidity's `Error(string)`, the revert output of `require`
+/// and `revert` with a message.
+const ERROR_SELECTOR: [u8; 4] = [0x08, 0xc3, 0x79, 0xa0];
+
+/// Decode the message of an `Error(string)` revert output.
+pub fn revert_reason(output: &[u8]) -> Option<String> {
+    if output.len() < 4 || output[0..4] != ERROR_SELECTOR {
+        return None;
+    }
+    let data = &output[4..];
+
+    let read_usize = |start: usize| -> Option<usize> {
+        if start + 32 > data.len() {
+            return None;
+        }
+        let value = U256::from(&data[start..(start + 32)]);
+        if value > U256::from(data.len()) {
+            return None;
+        }
+        Some(value.as_usize())
+    };
+
+    let offset = read_usize(0)?;
+    let length = read_usize(offset)?;
+    let start = offset + 32;
+    if start + length > data.len() {
+        return None;
+    }
+    String::from_utf8(data[start..(start + length)].to_vec()).ok()
+}
+
+/// Code of "execution reverted" errors, as returned by geth.
+const EXECUTION_REVERTED: i64 = 3;
+/// Generic code of server errors in the reserved range.
+const SERVER_ERROR: i64 = -32000;
+
+impl Error {
+    fn code(&self) -> ErrorCode {
+        match *self {
+            Error::InvalidParams(_) | Error::HexError(_) | Error::IntError(_) |
+            Error::RlpError(_) | Error::ECDSAError | Error::InvalidBlockNumber |
+            Error::InvalidPassword | Error::InvalidChainId =>
+                ErrorCode::InvalidParams,
+            Error::Reverted(_) => ErrorCode::ServerError(EXECUTION_REVERTED),
+            Error::InternalError(_) => ErrorCode::InternalError,
+            _ => ErrorCode::ServerError(SERVER_ERROR),
+        }
+    }
+
+    fn message(&self) -> String {
+        match *self {
+            Error::InvalidParams(ref detail) => format!("invalid params: {}", detail),
+            Error::HexError(ref detail) => format!("invalid hex: {}", detail),
+            Error::IntError(ref detail) => format!("invalid integer: {}", detail),
+            Error::UnsupportedTrieQuery => "unsupported trie query".to_string(),
+            Error::ECDSAError => "invalid signature or key".to_string(),
+            Error::NotFound => "not found".to_string(),
+            Error::UnknownAccount(address) => format!("unknown account 0x{:x}", address),
+            Error::RlpError(ref detail) => format!("invalid rlp: {}", detail),
+            Error::CallError(ref detail) => format!("invalid transaction: {}", detail),
+            Error::Reverted(ref output) => match revert_reason(output) {
+                Some(reason) => format!("execution reverted: {}", reason),
+                None => "execution reverted".to_string(),
+            },
+            Error::ExecutionFailed(ref detail) => format!("execution failed: {}", detail),
+            Error::GasRequiredExceedsAllowance(cap) => {
+                let cap: U256 = cap.into();
+                format!("gas required exceeds allowance ({})", cap)
+            },
+            Error::ExceedsBlockGasLimit => "exceeds block gas limit".to_string(),
+            Error::NonceTooLow => "nonce too low".to_string(),
+            Error::ReplacementUnderpriced => "replacement transaction underpriced".to_string(),
+            Error::UnknownSourceMapJump => "unknown source map jump".to_string(),
+            Error::IOError(ref detail) => format!("io error: {}", detail),
+            Error::InvalidBlockNumber => "invalid block number".to_string(),
+            Error::ParentHashMismatch => "parent hash mismatch".to_string(),
+            Error::BlockHashMismatch(number) => format!("block {} differs from the local one", number),
+            Error::StateRootMismatch => "state root mismatch".to_string(),
+            Error::ReceiptsRootMismatch => "receipts root mismatch".to_string(),
+            Error::GasUsedMismatch => "gas used mismatch".to_string(),
+            Error::UpstreamError => "upstream node request failed".to_string(),
+            Error::AccountLocked => "account locked".to_string(),
+            Error::InvalidPassword => "invalid password".to_string(),
+            Error::InvalidChainId => "invalid chain id".to_string(),
+            Error::InternalError(ref detail) => format!("internal error: {}", detail),
+        }
+    }
+}
+
 impl Into<jsonrpc_core::Error> for Error {
     fn into(self) -> jsonrpc_core::Error {
-        jsonrpc_core::Error::invalid_request()
+        jsonrpc_core::Error {
+            code: self.code(),
+            message: self.message(),
+            data: match self {
+                Error::Reverted(ref data) => Some(Value::String(to_hex(data))),
+                _ => None,
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use hexutil::read_hex;
+
+    #[test]
+    fn decode_revert_reason() {
+        let output = read_hex(concat!(
+            "0x08c379a0",
+            "0000000000000000000000000000000000000000000000000000000000000020",
+            "000000000000000000000000000000000000000000000000000000000000001a",
+            "4e6f7420656e6f7567682045746865722070726f76696465642e000000000000",
+        )).unwrap();
+
+        assert_eq!(revert_reason(&output), Some("Not enough Ether provided.".to_string()));
+        assert_eq!(revert_reason(&output[..40]), None);
+        assert_eq!(revert_reason(&[]), None);
     }
 }
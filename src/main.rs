@@ -3,6 +3,12 @@ extern crate sputnikvm_stateful;
 extern crate secp256k1;
 extern crate rand;
 extern crate sha3;
+extern crate sha2;
+extern crate hmac;
+extern crate bip39;
+extern crate scrypt;
+extern crate pbkdf2;
+extern crate aes_ctr;
 extern crate blockchain;
 extern crate bigint;
 extern crate rlp;
@@ -39,18 +45,19 @@ extern crate hyper;
 mod error;
 mod miner;
 mod rpc;
+mod wallet;
 
 #[cfg(feature = "frontend")]
 mod assets;
 
-use miner::MinerState;
-use rand::os::OsRng;
+use miner::{MiningMode, BlockReward, Genesis, ether};
 use secp256k1::key::{PublicKey, SecretKey};
 use secp256k1::SECP256K1;
-use bigint::U256;
+use bigint::{U256, Gas, Address};
 use hexutil::*;
 use std::thread;
 use std::str::FromStr;
+use std::path::{Path, PathBuf};
 use std::sync::{Arc, Mutex};
 use std::sync::mpsc::{channel, Sender, Receiver};
 use sputnikvm::Patch;
@@ -89,6 +96,34 @@ use sputnikvm_network_ubiq::{
     SpuriousDragonPatch as PUbiqSpuriousDragon,
 };
 
+/// Network id and EIP-155 chain id of a chain preset, and whether its
+/// patch signs transactions with the chain id.
+#[derive(Clone, Copy)]
+struct ChainIds {
+    network_id: u64,
+    chain_id: u64,
+    eip155: bool,
+}
+
+impl ChainIds {
+    fn pre_eip155(self) -> ChainIds {
+        ChainIds { eip155: false, ..self }
+    }
+}
+
+const CLASSIC: ChainIds = ChainIds { network_id: 1, chain_id: 61, eip155: true };
+const MORDEN: ChainIds = ChainIds { network_id: 2, chain_id: 62, eip155: true };
+const FOUNDATION: ChainIds = ChainIds { network_id: 1, chain_id: 1, eip155: true };
+const ELLAISM: ChainIds = ChainIds { network_id: 64, chain_id: 64, eip155: true };
+const EXPANSE: ChainIds = ChainIds { network_id: 1, chain_id: 2, eip155: true };
+const MUSICOIN: ChainIds = ChainIds { network_id: 7762959, chain_id: 7762959, eip155: false };
+const UBIQ: ChainIds = ChainIds { network_id: 88, chain_id: 8, eip155: true };
+
+fn validate_mining_mode(val: String) -> Result<(), String> {
+    MiningMode::from_str(&val).map(|_| ())
+        .map_err(|_| "expected auto, manual or a non-zero interval in milliseconds".to_string())
+}
+
 fn main() {
     env_logger::init();
 
@@ -97,63 +132,81 @@ fn main() {
             (version: "0.1")
             (author: "Ethereum Classic Contributors")
             (about: "SputnikVM Development Environment, a replacement for ethereumjs-testrpc.")
-            (@arg PRIVATE_KEY: -k --private +takes_value "Private key for the account to be generated, if not provided, a random private key will be generated.")
+            (@arg PRIVATE_KEY: -k --private +takes_value "Private key for the first account, if not provided, all accounts are derived from the mnemonic.")
+            (@arg MNEMONIC: --mnemonic +takes_value "BIP-39 mnemonic to derive the accounts from, if not provided, a random mnemonic will be generated and printed.")
+            (@arg DERIVATION_PATH: --("derivation-path") +takes_value "BIP-32 path of the accounts, without the final index, default to m/44'/61'/0'/0 for Classic and the SLIP-44 coin type of other chains.")
             (@arg BALANCE: -b --balance +takes_value "Balance in Wei for the account to be generated, default is 0x10000000000000000000000000000.")
             (@arg LISTEN: -l --listen +takes_value "Listen address and port for the RPC, e.g. 127.0.0.1:8545.")
             (@arg ACCOUNTS: -a --accounts +takes_value "Additional accounts to be generated, default to 9.")
             (@arg CHAIN: -c --chain +takes_value "Specify the chain to use. Refer to the documentation for a full list of valid values.")
+            (@arg NETWORK_ID: --("network-id") +takes_value "Network id returned by net_version, default to that of the chain.")
+            (@arg CHAIN_ID: --("chain-id") +takes_value "EIP-155 chain id returned by eth_chainId and used to sign transactions, default to that of the chain.")
+            (@arg COINBASE: --coinbase +takes_value "Address receiving block rewards and transaction fees, default to the first generated account.")
+            (@arg GAS_LIMIT: -g --gaslimit +takes_value "Block gas limit, default is 0x7a1200, or the gas limit of the genesis file.")
+            (@arg DATADIR: -d --datadir +takes_value "Directory to store the chain in, so that it can be resumed after a restart. If not provided, the chain is kept in memory.")
+            (@arg KEYSTORE: --keystore +takes_value "Directory of the key files of accounts created with personal_newAccount and personal_importRawKey, default to the keystore directory in the data directory. If neither is provided, the keys are kept in memory.")
+            (@arg FORK_URL: --("fork-url") +takes_value "HTTP JSON-RPC endpoint of an upstream node. Accounts, code and storage missing locally are fetched from it.")
+            (@arg FORK_BLOCK: --("fork-block") +takes_value requires[FORK_URL] "Upstream block to fork from, default to its latest block.")
+            (@arg GENESIS: --genesis +takes_value "Genesis file in the geth format, with accounts to pre-allocate and genesis block header fields.")
+            (@arg LOAD_STATE: --("load-state") +takes_value conflicts_with[GENESIS] "State dump written by svm_exportState, used to create the genesis accounts.")
+            (@arg MINING: -m --mining +takes_value {validate_mining_mode} "Mining strategy, either \"auto\" (one block per transaction), \"manual\" (only mine on request), or a block interval in milliseconds, default is auto.")
+            (@subcommand export =>
+                (about: "Export all blocks of the chain to a file as concatenated RLP, then exit.")
+                (@arg FILE: +required "File to write the blocks to."))
+            (@subcommand import =>
+                (about: "Re-execute and append blocks from a file of concatenated RLP, then exit.")
+                (@arg FILE: +required "File to read the blocks from."))
+            (@subcommand dump =>
+                (about: "Export the accounts of the current block to a state dump, as svm_exportState does, then exit.")
+                (@arg FILE: +required "File to write the state dump to."))
     ).get_matches();
 
+    let classic_reward = BlockReward::Era { base: ether(5), era_length: 5000000 };
+    let morden_reward = BlockReward::Era { base: ether(5), era_length: 2000000 };
+
     match matches.value_of("CHAIN") {
-        None => with_patch::<PClassicEIP160>(matches),
-
-        Some("classic") => with_patch::<PClassicEIP160>(matches),
-        Some("classic-eip160") => with_patch::<PClassicEIP160>(matches),
-        Some("classic-eip150") => with_patch::<PClassicEIP150>(matches),
-        Some("classic-homestead") => with_patch::<PClassicHomestead>(matches),
-        Some("classic-frontier") => with_patch::<PClassicFrontier>(matches),
-
-        Some("morden") => with_patch::<PMordenEIP160>(matches),
-        Some("morden-eip160") => with_patch::<PMordenEIP160>(matches),
-        Some("morden-eip150") => with_patch::<PMordenEIP150>(matches),
-        Some("morden-homestead") => with_patch::<PMordenHomestead>(matches),
-        Some("morden-frontier") => with_patch::<PMordenFrontier>(matches),
-
-        Some("foundation") => with_patch::<PFoundationByzantium>(matches),
-        Some("foundation-byzantium") => with_patch::<PFoundationByzantium>(matches),
-        Some("foundation-spurious-dragon") => with_patch::<PFoundationSpuriousDragon>(matches),
-        Some("foundation-eip150") => with_patch::<PFoundationEIP150>(matches),
-        Some("foundation-homestead") => with_patch::<PFoundationHomestead>(matches),
-        Some("foundation-frontier") => with_patch::<PFoundationFrontier>(matches),
-
-        Some("ellaism") => with_patch::<PEllaismEIP160>(matches),
-        Some("ellaism-eip160") => with_patch::<PEllaismEIP160>(matches),
-
-        Some("expanse") => with_patch::<PExpanseByzantium>(matches),
-        Some("expanse-byzantium") => with_patch::<PExpanseByzantium>(matches),
-        Some("expanse-spurious-dragon") => with_patch::<PExpanseSpuriousDragon>(matches),
-        Some("expanse-homestead") => with_patch::<PExpanseHomestead>(matches),
-        Some("expanse-frontier") => with_patch::<PExpanseFrontier>(matches),
-
-        Some("musicoin") => with_patch::<PMusicoinHomestead>(matches),
-        Some("musicoin-homestead") => with_patch::<PMusicoinHomestead>(matches),
-        Some("musicoin-frontier") => with_patch::<PMusicoinFrontier>(matches),
-
-        Some("ubiq") => with_patch::<PUbiqSpuriousDragon>(matches),
-        Some("ubiq-spurious-dragon") => with_patch::<PUbiqSpuriousDragon>(matches),
+        None => with_patch::<PClassicEIP160>(matches, classic_reward, CLASSIC),
+
+        Some("classic") => with_patch::<PClassicEIP160>(matches, classic_reward, CLASSIC),
+        Some("classic-eip160") => with_patch::<PClassicEIP160>(matches, classic_reward, CLASSIC),
+        Some("classic-eip150") => with_patch::<PClassicEIP150>(matches, classic_reward, CLASSIC.pre_eip155()),
+        Some("classic-homestead") => with_patch::<PClassicHomestead>(matches, classic_reward, CLASSIC.pre_eip155()),
+        Some("classic-frontier") => with_patch::<PClassicFrontier>(matches, classic_reward, CLASSIC.pre_eip155()),
+
+        Some("morden") => with_patch::<PMordenEIP160>(matches, morden_reward, MORDEN),
+        Some("morden-eip160") => with_patch::<PMordenEIP160>(matches, morden_reward, MORDEN),
+        Some("morden-eip150") => with_patch::<PMordenEIP150>(matches, morden_reward, MORDEN.pre_eip155()),
+        Some("morden-homestead") => with_patch::<PMordenHomestead>(matches, morden_reward, MORDEN.pre_eip155()),
+        Some("morden-frontier") => with_patch::<PMordenFrontier>(matches, morden_reward, MORDEN.pre_eip155()),
+
+        Some("foundation") => with_patch::<PFoundationByzantium>(matches, BlockReward::Fixed(ether(3)), FOUNDATION),
+        Some("foundation-byzantium") => with_patch::<PFoundationByzantium>(matches, BlockReward::Fixed(ether(3)), FOUNDATION),
+        Some("foundation-spurious-dragon") => with_patch::<PFoundationSpuriousDragon>(matches, BlockReward::Fixed(ether(5)), FOUNDATION),
+        Some("foundation-eip150") => with_patch::<PFoundationEIP150>(matches, BlockReward::Fixed(ether(5)), FOUNDATION.pre_eip155()),
+        Some("foundation-homestead") => with_patch::<PFoundationHomestead>(matches, BlockReward::Fixed(ether(5)), FOUNDATION.pre_eip155()),
+        Some("foundation-frontier") => with_patch::<PFoundationFrontier>(matches, BlockReward::Fixed(ether(5)), FOUNDATION.pre_eip155()),
+
+        Some("ellaism") => with_patch::<PEllaismEIP160>(matches, BlockReward::Fixed(ether(5)), ELLAISM),
+        Some("ellaism-eip160") => with_patch::<PEllaismEIP160>(matches, BlockReward::Fixed(ether(5)), ELLAISM),
+
+        Some("expanse") => with_patch::<PExpanseByzantium>(matches, BlockReward::Fixed(ether(4)), EXPANSE),
+        Some("expanse-byzantium") => with_patch::<PExpanseByzantium>(matches, BlockReward::Fixed(ether(4)), EXPANSE),
+        Some("expanse-spurious-dragon") => with_patch::<PExpanseSpuriousDragon>(matches, BlockReward::Fixed(ether(8)), EXPANSE),
+        Some("expanse-homestead") => with_patch::<PExpanseHomestead>(matches, BlockReward::Fixed(ether(8)), EXPANSE.pre_eip155()),
+        Some("expanse-frontier") => with_patch::<PExpanseFrontier>(matches, BlockReward::Fixed(ether(8)), EXPANSE.pre_eip155()),
+
+        Some("musicoin") => with_patch::<PMusicoinHomestead>(matches, BlockReward::Fixed(ether(314)), MUSICOIN),
+        Some("musicoin-homestead") => with_patch::<PMusicoinHomestead>(matches, BlockReward::Fixed(ether(314)), MUSICOIN),
+        Some("musicoin-frontier") => with_patch::<PMusicoinFrontier>(matches, BlockReward::Fixed(ether(314)), MUSICOIN),
+
+        Some("ubiq") => with_patch::<PUbiqSpuriousDragon>(matches, BlockReward::Fixed(ether(8)), UBIQ),
+        Some("ubiq-spurious-dragon") => with_patch::<PUbiqSpuriousDragon>(matches, BlockReward::Fixed(ether(8)), UBIQ),
 
         _ => panic!("Unsupported chain."),
     }
 }
 
-fn with_patch<'a, P: 'static + Patch + Send>(matches: clap::ArgMatches<'a>) {
-    let mut rng = OsRng::new().unwrap();
-
-    let secret_key = match matches.value_of("PRIVATE_KEY") {
-        Some(val) => SecretKey::from_slice(&SECP256K1, &read_hex(val).unwrap()).unwrap(),
-        None => SecretKey::new(&SECP256K1, &mut rng),
-    };
-
+fn with_patch<'a, P: 'static + Patch + Send>(matches: clap::ArgMatches<'a>, block_reward: BlockReward, chain_ids: ChainIds) {
     let balance = {
         let s = matches.value_of("BALANCE").unwrap_or("0x10000000000000000000000000000");
         if s.starts_with("0x") {
@@ -167,16 +220,115 @@ fn with_patch<'a, P: 'static + Patch + Send>(matches: clap::ArgMatches<'a>) {
         None => 9,
     };
 
+    let mnemonic = match matches.value_of("MNEMONIC") {
+        Some(val) => val.to_string(),
+        None => wallet::generate_mnemonic(),
+    };
+    // A resumed chain keeps the accounts it was created with, which this
+    // mnemonic may not derive.
+    let resuming = matches.value_of("DATADIR").map(|path| miner::has_chain(Path::new(path))).unwrap_or(false);
+    if !resuming {
+        println!("mnemonic: {}", mnemonic);
+    }
+    let derivation_path = matches.value_of("DERIVATION_PATH")
+        .unwrap_or(wallet::default_derivation_path(matches.value_of("CHAIN")));
+
     let mut genesis = Vec::new();
-    genesis.push((secret_key, balance));
+    let derived_len = match matches.value_of("PRIVATE_KEY") {
+        Some(val) => {
+            genesis.push((SecretKey::from_slice(&SECP256K1, &read_hex(val).unwrap()).unwrap(), balance));
+            accounts_len
+        },
+        None => accounts_len + 1,
+    };
 
-    for _ in 0..accounts_len {
-        genesis.push((SecretKey::new(&SECP256K1, &mut rng), balance));
+    for secret_key in wallet::derive_keys(&mnemonic, derivation_path, derived_len).unwrap() {
+        genesis.push((secret_key, balance));
     }
 
+    let genesis_file = match (matches.value_of("GENESIS"), matches.value_of("LOAD_STATE")) {
+        (Some(path), _) => Some(Genesis::from_file(Path::new(path)).unwrap()),
+        (None, Some(path)) => Some(rpc::read_state_file(Path::new(path)).unwrap()),
+        (None, None) => None,
+    };
+
+    let gas_limit = match matches.value_of("GAS_LIMIT") {
+        Some(s) => {
+            if s.starts_with("0x") {
+                Gas::from_str(s).unwrap()
+            } else {
+                Gas::from(U256::from_dec_str(s).unwrap())
+            }
+        },
+        None => genesis_file.as_ref().and_then(|genesis| genesis.gas_limit)
+            .unwrap_or(Gas::from(0x7a1200usize)),
+    };
+
+    let mining_mode = match matches.value_of("MINING") {
+        Some(val) => MiningMode::from_str(val).unwrap(),
+        None => MiningMode::Automine,
+    };
+
+    let fork = matches.value_of("FORK_URL").map(|url| {
+        let block = matches.value_of("FORK_BLOCK").map(|s| {
+            if s.starts_with("0x") {
+                U256::from_str(s).unwrap()
+            } else {
+                U256::from_dec_str(s).unwrap()
+            }
+        });
+        (url, block)
+    });
+
     let (sender, receiver) = channel::<bool>();
 
-    let state = miner::make_state::<P>(genesis);
+    let mut state = miner::make_state::<P>(genesis, gas_limit, genesis_file, fork, matches.value_of("DATADIR").map(Path::new));
+
+    let keystore = match (matches.value_of("KEYSTORE"), matches.value_of("DATADIR")) {
+        (Some(path), _) => Some(PathBuf::from(path)),
+        (None, Some(path)) => Some(Path::new(path).join("keystore")),
+        (None, None) => None,
+    };
+    if let Some(keystore) = keystore {
+        state.open_keystore(keystore).unwrap();
+    }
+
+    let coinbase = match matches.value_of("COINBASE") {
+        Some(val) => Address::from_str(val).unwrap(),
+        None => state.accounts()[0],
+    };
+    let network_id = match matches.value_of("NETWORK_ID") {
+        Some(val) => val.parse().unwrap(),
+        None => chain_ids.network_id,
+    };
+    let chain_id = match matches.value_of("CHAIN_ID") {
+        Some(val) => val.parse().unwrap(),
+        None => chain_ids.chain_id,
+    };
+    state.set_chain_ids(network_id, chain_id, chain_ids.eip155);
+    state.set_mining_mode(mining_mode);
+    state.set_coinbase(coinbase);
+    state.set_block_reward(block_reward);
+
+    match matches.subcommand() {
+        ("export", Some(sub_matches)) => {
+            let count = miner::export_blocks(&state, Path::new(sub_matches.value_of("FILE").unwrap())).unwrap();
+            println!("exported blocks: {}", count);
+            return;
+        },
+        ("import", Some(sub_matches)) => {
+            let count = miner::import_blocks::<P>(&mut state, Path::new(sub_matches.value_of("FILE").unwrap())).unwrap();
+            println!("imported blocks: {}", count);
+            return;
+        },
+        ("dump", Some(sub_matches)) => {
+            let number = state.block_height();
+            rpc::write_state_file(&state, number, Path::new(sub_matches.value_of("FILE").unwrap())).unwrap();
+            println!("exported state at block: {}", number);
+            return;
+        },
+        _ => (),
+    }
 
     let miner_arc = Arc::new(Mutex::new(state));
     let rpc_arc = miner_arc.clone();
@@ -0,0 +1,87 @@
+use bigint::H256;
+use trie::{Database, DatabaseGuard};
+use sputnikvm_stateful::Stateful;
+use std::collections::HashMap;
+use std::path::Path;
+use std::sync::Mutex;
+
+use error::Error;
+use super::datadir::RecordLog;
+
+const NODE: u8 = b'n';
+
+pub type ChainStateful = Stateful<'static, ChainDatabase>;
+
+/// Trie node storage for the chain state. Nodes are always kept in
+/// memory, and once a file is opened, every new node is also appended to
+/// it.
+pub struct ChainDatabase {
+    nodes: Mutex<HashMap<H256, Vec<u8>>>,
+    log: Mutex<Option<RecordLog>>,
+}
+
+/// Access to the nodes of a `ChainDatabase`. Like `MemoryDatabase`, it
+/// only locks for the duration of each read or write, as `Stateful` holds
+/// several guards at once.
+pub struct ChainDatabaseGuard<'a> {
+    nodes: &'a Mutex<HashMap<H256, Vec<u8>>>,
+    log: &'a Mutex<Option<RecordLog>>,
+}
+
+impl ChainDatabase {
+    pub fn new() -> Self {
+        ChainDatabase {
+            nodes: Mutex::new(HashMap::new()),
+            log: Mutex::new(None),
+        }
+    }
+
+    /// Load all nodes stored in the file, and write new nodes to it from
+    /// now on.
+    pub fn open(&self, path: &Path) -> Result<(), Error> {
+        let (log, records) = RecordLog::open(path)?;
+
+        let mut nodes = self.nodes.lock().unwrap();
+        for (tag, key, value) in records {
+            if tag == NODE {
+                nodes.insert(H256::from(key.as_slice()), value);
+            }
+        }
+        *self.log.lock().unwrap() = Some(log);
+
+        Ok(())
+    }
+}
+
+impl<'a> Database<'a> for ChainDatabase {
+    type Guard = ChainDatabaseGuard<'a>;
+
+    fn create_guard(&'a self) -> ChainDatabaseGuard<'a> {
+        ChainDatabaseGuard {
+            nodes: &self.nodes,
+            log: &self.log,
+        }
+    }
+}
+
+impl<'a> DatabaseGuard for ChainDatabaseGuard<'a> {
+    fn get(&self, hash: H256) -> Option<Vec<u8>> {
+        self.nodes.lock().unwrap().get(&hash).cloned()
+    }
+
+    fn set(&mut self, hash: H256, value: Vec<u8>) {
+        let mut nodes = self.nodes.lock().unwrap();
+        if nodes.contains_key(&hash) {
+            return;
+        }
+
+        // `DatabaseGuard::set` cannot fail, so a failed write is only
+        // logged, and the node is kept in memory either way.
+        if let Some(ref mut log) = *self.log.lock().unwrap() {
+            if let Err(err) = log.append(NODE, &hash, &value) {
+                error!("Failed to write a state node to the data directory: {:?}", err);
+            }
+        }
+        nodes.insert(hash, value);
+    }
+}
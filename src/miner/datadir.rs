@@ -0,0 +1,417 @@
+use rlp::{self, RlpStream, UntrustedRlp};
+use block::{Block, Receipt};
+use bigint::{H256, M256, U256, Address};
+use secp256k1::SECP256K1;
+use secp256k1::key::SecretKey;
+use std::collections::HashMap;
+use std::fs::{self, File, OpenOptions};
+use std::io::{Read, Write, BufReader};
+use std::path::Path;
+
+use error::Error;
+use super::fork::LocalChanges;
+
+/// An append-only file of tagged key-value records.
+pub struct RecordLog {
+    file: File,
+}
+
+impl RecordLog {
+    /// Open the file, creating it if it does not exist, and return every
+    /// record already in it. A record cut short by an interrupted write is
+    /// ignored.
+    pub fn open(path: &Path) -> Result<(RecordLog, Vec<(u8, Vec<u8>, Vec<u8>)>), Error> {
+        let mut file = OpenOptions::new().read(true).append(true).create(true).open(path)?;
+
+        let mut buffer = Vec::new();
+        BufReader::new(&mut file).read_to_end(&mut buffer)?;
+
+        let mut records = Vec::new();
+        let mut rest = &buffer[..];
+        while let Some((tag, key, value, next)) = read_record(rest) {
+            records.push((tag, key.to_vec(), value.to_vec()));
+            rest = next;
+        }
+
+        Ok((RecordLog { file }, records))
+    }
+
+    pub fn append(&mut self, tag: u8, key: &[u8], value: &[u8]) -> Result<(), Error> {
+        let mut buffer = Vec::with_capacity(9 + key.len() + value.len());
+        buffer.push(tag);
+        buffer.extend_from_slice(&encode_length(key.len()));
+        buffer.extend_from_slice(key);
+        buffer.extend_from_slice(&encode_length(value.len()));
+        buffer.extend_from_slice(value);
+
+        self.file.write_all(&buffer)?;
+        Ok(())
+    }
+}
+
+fn encode_length(length: usize) -> [u8; 4] {
+    let length = length as u32;
+    [(length >> 24) as u8, (length >> 16) as u8, (length >> 8) as u8, length as u8]
+}
+
+fn read_length(data: &[u8]) -> Option<(usize, &[u8])> {
+    if data.len() < 4 {
+        return None;
+    }
+    let length = ((data[0] as usize) << 24) | ((data[1] as usize) << 16) |
+        ((data[2] as usize) << 8) | (data[3] as usize);
+    Some((length, &data[4..]))
+}
+
+fn read_record(data: &[u8]) -> Option<(u8, &[u8], &[u8], &[u8])> {
+    if data.len() < 1 {
+        return None;
+    }
+    let tag = data[0];
+
+    let (key_length, data) = read_length(&data[1..])?;
+    if data.len() < key_length {
+        return None;
+    }
+    let (key, data) = data.split_at(key_length);
+
+    let (value_length, data) = read_length(data)?;
+    if data.len() < value_length {
+        return None;
+    }
+    let (value, data) = data.split_at(value_length);
+
+    Some((tag, key, value, data))
+}
+
+fn encode_number(number: usize) -> [u8; 8] {
+    let number = number as u64;
+    let mut ret = [0u8; 8];
+    for i in 0..8 {
+        ret[i] = (number >> (56 - 8 * i)) as u8;
+    }
+    ret
+}
+
+fn decode_number(data: &[u8]) -> Result<usize, Error> {
+    if data.len() != 8 {
+        return Err(Error::RlpError("invalid number length".to_string()));
+    }
+    let mut number = 0u64;
+    for byte in data {
+        number = (number << 8) | (*byte as u64);
+    }
+    Ok(number as usize)
+}
+
+fn storage_key(number: usize, address: Address) -> Vec<u8> {
+    let mut key = encode_number(number).to_vec();
+    key.extend_from_slice(&address);
+    key
+}
+
+fn encode_storage(storage: &HashMap<U256, M256>) -> Vec<u8> {
+    let mut stream = RlpStream::new_list(storage.len());
+    for (index, value) in storage {
+        stream.begin_list(2);
+        stream.append(index);
+        let value: U256 = (*value).into();
+        stream.append(&value);
+    }
+    stream.out().to_vec()
+}
+
+fn encode_local_changes(changes: &LocalChanges) -> Vec<u8> {
+    let mut stream = RlpStream::new_list(2);
+    stream.begin_list(changes.storage.len());
+    for (&(address, index), number) in &changes.storage {
+        stream.begin_list(3);
+        stream.append(&address);
+        stream.append(&index);
+        stream.append(&U256::from(*number));
+    }
+    stream.begin_list(changes.deleted_accounts.len());
+    for (address, number) in &changes.deleted_accounts {
+        stream.begin_list(2);
+        stream.append(address);
+        stream.append(&U256::from(*number));
+    }
+    stream.out().to_vec()
+}
+
+fn decode_local_changes(data: &[u8]) -> Result<LocalChanges, Error> {
+    let rlp = UntrustedRlp::new(data);
+    let mut changes = LocalChanges::default();
+    for item in rlp.at(0)?.iter() {
+        let number: U256 = item.val_at(2)?;
+        changes.storage.insert((item.val_at(0)?, item.val_at(1)?), number.as_usize());
+    }
+    for item in rlp.at(1)?.iter() {
+        let number: U256 = item.val_at(1)?;
+        changes.deleted_accounts.insert(item.val_at(0)?, number.as_usize());
+    }
+    Ok(changes)
+}
+
+fn decode_storage(data: &[u8]) -> Result<HashMap<U256, M256>, Error> {
+    let mut storage = HashMap::new();
+    for item in UntrustedRlp::new(data).iter() {
+        let index: U256 = item.val_at(0)?;
+        let value: U256 = item.val_at(1)?;
+        storage.insert(index, M256::from(value));
+    }
+    Ok(storage)
+}
+
+const ACCOUNT: u8 = b'k';
+const BLOCK: u8 = b'b';
+const RECEIPT: u8 = b'r';
+const STATUS: u8 = b's';
+const REVERT: u8 = b'v';
+const STORAGE: u8 = b'f';
+const STORAGE_CHANGES: u8 = b'c';
+const REMOVED: u8 = b'd';
+const REWIND: u8 = b'h';
+const STATE: u8 = b't';
+const SENDER: u8 = b'u';
+const FORK: u8 = b'p';
+const DIRTY: u8 = b'w';
+const LOCAL_CHANGES: u8 = b'l';
+
+/// A change to the chain that is written to the data directory.
+/// Replaying the records in order rebuilds the chain.
+pub enum Record {
+    Account(SecretKey),
+    Block(Block),
+    Receipt(H256, Receipt),
+    Status(H256, bool),
+    /// Output of a failed transaction, holding its revert reason.
+    Revert(H256, Vec<u8>),
+    /// Sender of an unsigned transaction from an impersonated account.
+    Sender(H256, Address),
+    /// Full storage of an account after the given block, or `None` if
+    /// the account was removed.
+    Storage(usize, Address, Option<HashMap<U256, M256>>),
+    /// Storage slots of an account changed in the given block. A zero
+    /// value removes the slot.
+    StorageChanges(usize, Address, HashMap<U256, M256>),
+    /// The chain was rolled back to the given block height.
+    Rewind(usize),
+    /// The current state was changed outside of a block, by `svm_set*` or
+    /// `evm_revert`, to the given root.
+    State(H256),
+    /// The chain was forked from the given upstream block.
+    Fork(U256),
+    /// Storage written and accounts deleted locally in fork mode, added to
+    /// the ones recorded before.
+    Dirty(LocalChanges),
+    /// All local changes in fork mode, replacing the ones recorded before
+    /// after `evm_revert`.
+    LocalChanges(LocalChanges),
+}
+
+/// Whether the data directory already holds a chain to resume.
+pub fn has_chain(path: &Path) -> bool {
+    fs::metadata(path.join("chain")).map(|metadata| metadata.len() > 0).unwrap_or(false)
+}
+
+/// Chain data stored on disk with `--datadir`. State trie nodes are kept
+/// in their own file, written by `ChainDatabase`.
+pub struct DataDir {
+    chain: RecordLog,
+}
+
+impl DataDir {
+    /// Open the data directory, creating it if needed, and return all
+    /// records written so far.
+    pub fn open(path: &Path) -> Result<(DataDir, Vec<Record>), Error> {
+        fs::create_dir_all(path)?;
+        let (chain, raw_records) = RecordLog::open(&path.join("chain"))?;
+
+        let mut records = Vec::new();
+        for (tag, key, value) in raw_records {
+            records.push(match tag {
+                ACCOUNT => Record::Account(SecretKey::from_slice(&SECP256K1, &value)?),
+                BLOCK => Record::Block(UntrustedRlp::new(&value).as_val()?),
+                RECEIPT => Record::Receipt(H256::from(key.as_slice()),
+                                           UntrustedRlp::new(&value).as_val()?),
+                STATUS => Record::Status(H256::from(key.as_slice()),
+                                         value.len() > 0 && value[0] == 1),
+                REVERT => Record::Revert(H256::from(key.as_slice()), value),
+                SENDER => Record::Sender(H256::from(key.as_slice()), Address::from(value.as_slice())),
+                STORAGE | STORAGE_CHANGES | REMOVED => {
+                    if key.len() != 28 {
+                        return Err(Error::RlpError("invalid storage key length".to_string()));
+                    }
+                    let number = decode_number(&key[0..8])?;
+                    let address = Address::from(&key[8..28]);
+
+                    match tag {
+                        REMOVED => Record::Storage(number, address, None),
+                        STORAGE => Record::Storage(number, address, Some(decode_storage(&value)?)),
+                        _ => Record::StorageChanges(number, address, decode_storage(&value)?),
+                    }
+                },
+                REWIND => Record::Rewind(decode_number(&key)?),
+                STATE => Record::State(H256::from(key.as_slice())),
+                FORK => Record::Fork(UntrustedRlp::new(&value).as_val()?),
+                DIRTY => Record::Dirty(decode_local_changes(&value)?),
+                LOCAL_CHANGES => Record::LocalChanges(decode_local_changes(&value)?),
+                _ => return Err(Error::RlpError(format!("unknown record tag {}", tag))),
+            });
+        }
+
+        Ok((DataDir { chain }, records))
+    }
+
+    pub fn write(&mut self, record: &Record) -> Result<(), Error> {
+        match record {
+            &Record::Account(ref secret_key) =>
+                self.chain.append(ACCOUNT, &[], &secret_key[..]),
+            &Record::Block(ref block) =>
+                self.chain.append(BLOCK, &[], &rlp::encode(block).to_vec()),
+            &Record::Receipt(hash, ref receipt) =>
+                self.chain.append(RECEIPT, &hash, &rlp::encode(receipt).to_vec()),
+            &Record::Status(hash, is_okay) =>
+                self.chain.append(STATUS, &hash, &[if is_okay { 1 } else { 0 }]),
+            &Record::Revert(hash, ref output) =>
+                self.chain.append(REVERT, &hash, output),
+            &Record::Sender(hash, sender) =>
+                self.chain.append(SENDER, &hash, &sender),
+            &Record::Storage(number, address, ref storage) => {
+                let key = storage_key(number, address);
+                match storage {
+                    &Some(ref storage) => self.chain.append(STORAGE, &key, &encode_storage(storage)),
+                    &None => self.chain.append(REMOVED, &key, &[]),
+                }
+            },
+            &Record::StorageChanges(number, address, ref changes) =>
+                self.chain.append(STORAGE_CHANGES, &storage_key(number, address), &encode_storage(changes)),
+            &Record::Rewind(height) =>
+                self.chain.append(REWIND, &encode_number(height), &[]),
+            &Record::State(root) =>
+                self.chain.append(STATE, &root, &[]),
+            &Record::Fork(block) =>
+                self.chain.append(FORK, &[], &rlp::encode(&block).to_vec()),
+            &Record::Dirty(ref changes) =>
+                self.chain.append(DIRTY, &[], &encode_local_changes(changes)),
+            &Record::LocalChanges(ref changes) =>
+                self.chain.append(LOCAL_CHANGES, &[], &encode_local_changes(changes)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use std::process;
+
+    fn temp_dir(name: &str) -> ::std::path::PathBuf {
+        let path = env::temp_dir().join(format!("svmdev-{}-{}", name, process::id()));
+        let _ = fs::remove_dir_all(&path);
+        path
+    }
+
+    #[test]
+    fn records_round_trip() {
+        let path = temp_dir("datadir");
+        let address = Address::from(&[0x11; 20][..]);
+        let hash = H256::from(&[0x22; 32][..]);
+        let secret_key = SecretKey::from_slice(&SECP256K1, &[0x33; 32]).unwrap();
+
+        let mut storage = HashMap::new();
+        storage.insert(U256::from(1u64), M256::from(2u64));
+        storage.insert(U256::from(3u64), M256::zero());
+
+        let mut changes = LocalChanges::default();
+        changes.storage.insert((address, U256::from(1u64)), 5);
+        changes.deleted_accounts.insert(address, 6);
+
+        {
+            let (mut datadir, records) = DataDir::open(&path).unwrap();
+            assert!(records.is_empty());
+
+            datadir.write(&Record::Account(secret_key.clone())).unwrap();
+            datadir.write(&Record::Status(hash, true)).unwrap();
+            datadir.write(&Record::Revert(hash, vec![1, 2, 3])).unwrap();
+            datadir.write(&Record::Storage(5, address, Some(storage.clone()))).unwrap();
+            datadir.write(&Record::Storage(6, address, None)).unwrap();
+            datadir.write(&Record::StorageChanges(7, address, storage.clone())).unwrap();
+            datadir.write(&Record::Rewind(4)).unwrap();
+            datadir.write(&Record::State(hash)).unwrap();
+            datadir.write(&Record::Sender(hash, address)).unwrap();
+            datadir.write(&Record::Fork(U256::from(1000u64))).unwrap();
+            datadir.write(&Record::Dirty(changes.clone())).unwrap();
+        }
+        assert!(has_chain(&path));
+
+        let (_, records) = DataDir::open(&path).unwrap();
+        assert_eq!(records.len(), 11);
+        match records[0] {
+            Record::Account(ref key) => assert_eq!(key, &secret_key),
+            _ => panic!("expected an account record"),
+        }
+        match records[1] {
+            Record::Status(h, is_okay) => assert!(h == hash && is_okay),
+            _ => panic!("expected a status record"),
+        }
+        match records[2] {
+            Record::Revert(h, ref output) => assert!(h == hash && output == &vec![1, 2, 3]),
+            _ => panic!("expected a revert record"),
+        }
+        match records[3] {
+            Record::Storage(5, a, Some(ref s)) => assert!(a == address && s == &storage),
+            _ => panic!("expected a storage record"),
+        }
+        match records[4] {
+            Record::Storage(6, a, None) => assert_eq!(a, address),
+            _ => panic!("expected a removed storage record"),
+        }
+        match records[5] {
+            Record::StorageChanges(7, a, ref s) => assert!(a == address && s == &storage),
+            _ => panic!("expected a storage changes record"),
+        }
+        match records[6] {
+            Record::Rewind(4) => (),
+            _ => panic!("expected a rewind record"),
+        }
+        match records[7] {
+            Record::State(root) => assert_eq!(root, hash),
+            _ => panic!("expected a state record"),
+        }
+        match records[8] {
+            Record::Sender(h, sender) => assert!(h == hash && sender == address),
+            _ => panic!("expected a sender record"),
+        }
+        match records[9] {
+            Record::Fork(block) => assert_eq!(block, U256::from(1000u64)),
+            _ => panic!("expected a fork record"),
+        }
+        match records[10] {
+            Record::Dirty(ref c) => assert_eq!(c, &changes),
+            _ => panic!("expected a dirty record"),
+        }
+
+        fs::remove_dir_all(&path).unwrap();
+    }
+
+    #[test]
+    fn truncated_record_is_ignored() {
+        let path = temp_dir("record-log").join("log");
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+
+        {
+            let (mut log, _) = RecordLog::open(&path).unwrap();
+            log.append(b'a', &[1], &[2, 3]).unwrap();
+            log.append(b'b', &[4], &[5, 6]).unwrap();
+        }
+        let length = fs::metadata(&path).unwrap().len();
+        OpenOptions::new().write(true).open(&path).unwrap().set_len(length - 1).unwrap();
+
+        let (_, records) = RecordLog::open(&path).unwrap();
+        assert_eq!(records, vec![(b'a', vec![1], vec![2, 3])]);
+
+        fs::remove_dir_all(path.parent().unwrap()).unwrap();
+    }
+}
@@ -0,0 +1,432 @@
+use bigint::{H256, M256, U256, Address};
+use hexutil::read_hex;
+use serde_json::{self, Value};
+use sputnikvm::AccountChange;
+use std::collections::HashMap;
+use std::hash::Hash;
+use std::io::{Read, Write};
+use std::net::TcpStream;
+use std::rc::Rc;
+use std::str::FromStr;
+use std::sync::Mutex;
+use std::time::Duration;
+
+use error::Error;
+use super::database::ChainStateful;
+use super::empty_storage;
+
+/// How long to wait on the upstream node before giving up on a request.
+const TIMEOUT: u64 = 30;
+
+#[derive(Serialize)]
+struct Request<'a> {
+    jsonrpc: &'a str,
+    id: usize,
+    method: &'a str,
+    params: Vec<Value>,
+}
+
+#[derive(Deserialize)]
+struct Response {
+    result: Option<Value>,
+}
+
+/// A JSON-RPC client over plain HTTP, enough to talk to a node on the
+/// local network.
+struct Client {
+    host: String,
+    path: String,
+}
+
+impl Client {
+    fn new(url: &str) -> Result<Client, Error> {
+        if !url.starts_with("http://") {
+            return Err(Error::InvalidParams(format!("fork url {} is not http://", url)));
+        }
+        let url = &url["http://".len()..];
+
+        let (host, path) = match url.find('/') {
+            Some(index) => (&url[..index], &url[index..]),
+            None => (url, "/"),
+        };
+        let host = if host.contains(':') {
+            host.to_string()
+        } else {
+            format!("{}:80", host)
+        };
+
+        Ok(Client { host, path: path.to_string() })
+    }
+
+    fn request(&self, method: &str, params: Vec<Value>) -> Result<Value, Error> {
+        let body = serde_json::to_string(&Request {
+            jsonrpc: "2.0", id: 1, method, params,
+        }).map_err(|_| Error::UpstreamError)?;
+
+        let mut stream = TcpStream::connect(&self.host[..])?;
+        stream.set_read_timeout(Some(Duration::from_secs(TIMEOUT)))?;
+        stream.set_write_timeout(Some(Duration::from_secs(TIMEOUT)))?;
+        // HTTP/1.0 so that the response is neither chunked nor kept alive.
+        write!(stream, "POST {} HTTP/1.0\r\nHost: {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\n\r\n{}",
+               self.path, self.host, body.len(), body)?;
+
+        let mut response = String::new();
+        stream.read_to_string(&mut response)?;
+
+        let body = match response.find("\r\n\r\n") {
+            Some(index) => &response[(index + 4)..],
+            None => return Err(Error::UpstreamError),
+        };
+        let response: Response = serde_json::from_str(body).map_err(|_| Error::UpstreamError)?;
+        response.result.ok_or(Error::UpstreamError)
+    }
+
+    fn request_str(&self, method: &str, params: Vec<Value>) -> Result<String, Error> {
+        match self.request(method, params)? {
+            Value::String(value) => Ok(value),
+            _ => Err(Error::UpstreamError),
+        }
+    }
+}
+
+fn hex_value<T: ::std::fmt::LowerHex>(value: T) -> Value {
+    Value::String(format!("0x{:x}", value))
+}
+
+fn read_u256(s: &str) -> Result<U256, Error> {
+    U256::from_str(s).map_err(|_| Error::HexError(s.to_string()))
+}
+
+/// Nonce, balance and code of an account.
+#[derive(Debug, Clone)]
+pub struct AccountInfo {
+    pub nonce: U256,
+    pub balance: U256,
+    pub code: Vec<u8>,
+}
+
+/// Storage written and accounts deleted locally, each with the first
+/// block it happened in, so that reads at earlier blocks still fall back
+/// to the upstream state.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct LocalChanges {
+    pub storage: HashMap<(Address, U256), usize>,
+    pub deleted_accounts: HashMap<Address, usize>,
+}
+
+impl LocalChanges {
+    pub fn is_empty(&self) -> bool {
+        self.storage.is_empty() && self.deleted_accounts.is_empty()
+    }
+}
+
+/// State of an upstream node at a pinned block. Everything fetched is
+/// cached, as the upstream state at that block never changes.
+pub struct Fork {
+    client: Client,
+    block: U256,
+    accounts: Mutex<HashMap<Address, Option<AccountInfo>>>,
+    storage: Mutex<HashMap<(Address, U256), M256>>,
+    block_hashes: Mutex<HashMap<U256, H256>>,
+    changes: Mutex<LocalChanges>,
+}
+
+/// Record `number` for the key, unless an earlier block is already
+/// recorded. Returns whether anything changed.
+fn mark<K: Hash + Eq>(marks: &mut HashMap<K, usize>, key: K, number: usize) -> bool {
+    match marks.get(&key) {
+        Some(first) if *first <= number => return false,
+        _ => (),
+    }
+    marks.insert(key, number);
+    true
+}
+
+impl Fork {
+    /// Connect to the upstream node, pinning the given block, or its
+    /// latest block if none is given.
+    pub fn new(url: &str, block: Option<U256>) -> Result<Fork, Error> {
+        let client = Client::new(url)?;
+        let block = match block {
+            Some(block) => block,
+            None => read_u256(&client.request_str("eth_blockNumber", Vec::new())?)?,
+        };
+
+        Ok(Fork {
+            client, block,
+            accounts: Mutex::new(HashMap::new()),
+            storage: Mutex::new(HashMap::new()),
+            block_hashes: Mutex::new(HashMap::new()),
+            changes: Mutex::new(LocalChanges::default()),
+        })
+    }
+
+    pub fn block_number(&self) -> U256 {
+        self.block
+    }
+
+    /// The account at the pinned block, or `None` if it is empty.
+    pub fn account(&self, address: Address) -> Result<Option<AccountInfo>, Error> {
+        if let Some(account) = self.accounts.lock().unwrap().get(&address) {
+            return Ok(account.clone());
+        }
+
+        let params = vec![hex_value(address), hex_value(self.block)];
+        let nonce = read_u256(&self.client.request_str("eth_getTransactionCount", params.clone())?)?;
+        let balance = read_u256(&self.client.request_str("eth_getBalance", params.clone())?)?;
+        let code = read_hex(&self.client.request_str("eth_getCode", params)?)?;
+
+        let account = if nonce == U256::zero() && balance == U256::zero() && code.len() == 0 {
+            None
+        } else {
+            Some(AccountInfo { nonce, balance, code })
+        };
+        self.accounts.lock().unwrap().insert(address, account.clone());
+        Ok(account)
+    }
+
+    pub fn storage(&self, address: Address, index: U256) -> Result<M256, Error> {
+        if let Some(value) = self.storage.lock().unwrap().get(&(address, index)) {
+            return Ok(*value);
+        }
+
+        let params = vec![hex_value(address), hex_value(index), hex_value(self.block)];
+        let value = M256::from(read_u256(&self.client.request_str("eth_getStorageAt", params)?)?);
+        self.storage.lock().unwrap().insert((address, index), value);
+        Ok(value)
+    }
+
+    pub fn block_hash(&self, number: U256) -> Result<H256, Error> {
+        if let Some(hash) = self.block_hashes.lock().unwrap().get(&number) {
+            return Ok(*hash);
+        }
+
+        let block = self.client.request("eth_getBlockByNumber", vec![hex_value(number), Value::Bool(false)])?;
+        let hash = match block.get("hash") {
+            Some(&Value::String(ref hash)) => H256::from_str(hash).map_err(|_| Error::HexError(hash.clone()))?,
+            _ => return Err(Error::UpstreamError),
+        };
+        self.block_hashes.lock().unwrap().insert(number, hash);
+        Ok(hash)
+    }
+
+    /// Copy an upstream account into the local state of block `number`,
+    /// unless the local state already has it or it was deleted locally.
+    /// Its storage stays upstream until written.
+    pub fn materialize(&self, stateful: &mut ChainStateful, address: Address, number: usize) -> Result<(), Error> {
+        if stateful.state_of(stateful.root()).get(&address).is_some() || self.is_deleted(address, number) {
+            return Ok(());
+        }
+
+        if let Some(account) = self.account(address)? {
+            stateful.transit(&[AccountChange::Create {
+                nonce: account.nonce,
+                address,
+                balance: account.balance,
+                storage: empty_storage(address),
+                code: Rc::new(account.code),
+            }]);
+        }
+        Ok(())
+    }
+
+    /// Remember storage written and accounts deleted locally in block
+    /// `number`, so that from that block on they are no longer read from
+    /// upstream, even when storage is set back to zero. Returns what was
+    /// not already known.
+    pub fn mark_dirty(&self, number: usize, accounts: &[AccountChange]) -> LocalChanges {
+        let mut changes = self.changes.lock().unwrap();
+        let mut marked = LocalChanges::default();
+
+        for account in accounts {
+            match account.clone() {
+                AccountChange::Full { address, changing_storage, .. } => {
+                    let changing_storage: HashMap<U256, M256> = changing_storage.into();
+                    for index in changing_storage.keys() {
+                        if mark(&mut changes.storage, (address, *index), number) {
+                            marked.storage.insert((address, *index), number);
+                        }
+                    }
+                },
+                AccountChange::Create { address, storage, .. } => {
+                    let storage: HashMap<U256, M256> = storage.into();
+                    for index in storage.keys() {
+                        if mark(&mut changes.storage, (address, *index), number) {
+                            marked.storage.insert((address, *index), number);
+                        }
+                    }
+                },
+                AccountChange::Nonexist(address) => {
+                    if mark(&mut changes.deleted_accounts, address, number) {
+                        marked.deleted_accounts.insert(address, number);
+                    }
+                },
+                _ => (),
+            }
+        }
+
+        marked
+    }
+
+    /// Add local changes returned by `mark_dirty` earlier, when the chain
+    /// is resumed from a data directory.
+    pub fn merge_local_changes(&self, marked: LocalChanges) {
+        let mut changes = self.changes.lock().unwrap();
+        for (key, number) in marked.storage {
+            mark(&mut changes.storage, key, number);
+        }
+        for (address, number) in marked.deleted_accounts {
+            mark(&mut changes.deleted_accounts, address, number);
+        }
+    }
+
+    /// Whether the slot was written locally at or before block `number`.
+    pub fn is_dirty(&self, address: Address, index: U256, number: usize) -> bool {
+        match self.changes.lock().unwrap().storage.get(&(address, index)) {
+            Some(first) => *first <= number,
+            None => false,
+        }
+    }
+
+    /// Whether the account was deleted locally at or before block `number`.
+    pub fn is_deleted(&self, address: Address, number: usize) -> bool {
+        match self.changes.lock().unwrap().deleted_accounts.get(&address) {
+            Some(first) => *first <= number,
+            None => false,
+        }
+    }
+
+    /// Forget local changes made after block `height`, when the chain is
+    /// rolled back to it.
+    pub fn rewind(&self, height: usize) {
+        let mut changes = self.changes.lock().unwrap();
+        changes.storage.retain(|_, first| *first <= height);
+        changes.deleted_accounts.retain(|_, first| *first <= height);
+    }
+
+    pub fn local_changes(&self) -> LocalChanges {
+        self.changes.lock().unwrap().clone()
+    }
+
+    /// Go back to local changes saved earlier, when a snapshot is
+    /// reverted.
+    pub fn set_local_changes(&self, changes: LocalChanges) {
+        *self.changes.lock().unwrap() = changes;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::TcpListener;
+    use std::sync::Arc;
+    use std::thread;
+
+    /// Serve canned answers over HTTP on a local port, recording the
+    /// method of every request.
+    fn stub_node() -> (String, Arc<Mutex<Vec<String>>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/", listener.local_addr().unwrap());
+        let methods = Arc::new(Mutex::new(Vec::new()));
+
+        let served = methods.clone();
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                let mut stream = stream.unwrap();
+                let mut request = Vec::new();
+                let mut buffer = [0u8; 1024];
+                let body = loop {
+                    let read = stream.read(&mut buffer).unwrap();
+                    request.extend_from_slice(&buffer[..read]);
+                    let text = String::from_utf8_lossy(&request).into_owned();
+                    if let Some(index) = text.find("\r\n\r\n") {
+                        let length: usize = text.lines()
+                            .find(|line| line.starts_with("Content-Length: "))
+                            .map(|line| line["Content-Length: ".len()..].parse().unwrap())
+                            .unwrap();
+                        if text.len() >= index + 4 + length {
+                            break text[(index + 4)..].to_string();
+                        }
+                    }
+                };
+
+                let request: Value = serde_json::from_str(&body).unwrap();
+                let method = request["method"].as_str().unwrap().to_string();
+                let result = match &method[..] {
+                    "eth_blockNumber" => r#""0x10""#,
+                    "eth_getTransactionCount" => r#""0x1""#,
+                    "eth_getBalance" => r#""0x64""#,
+                    "eth_getCode" => r#""0x6000""#,
+                    "eth_getStorageAt" => r#""0x2a""#,
+                    "eth_getBlockByNumber" =>
+                        r#"{ "hash": "0x1111111111111111111111111111111111111111111111111111111111111111" }"#,
+                    _ => "null",
+                };
+                served.lock().unwrap().push(method);
+
+                let body = format!(r#"{{ "jsonrpc": "2.0", "id": 1, "result": {} }}"#, result);
+                write!(stream, "HTTP/1.0 200 OK\r\nContent-Type: application/json\r\nContent-Length: {}\r\n\r\n{}",
+                       body.len(), body).unwrap();
+            }
+        });
+
+        (url, methods)
+    }
+
+    #[test]
+    fn fetch_and_cache_upstream_state() {
+        let (url, methods) = stub_node();
+        let address = Address::from(&[0x11; 20][..]);
+
+        let fork = Fork::new(&url, None).unwrap();
+        assert_eq!(fork.block_number(), U256::from(0x10u64));
+
+        let account = fork.account(address).unwrap().unwrap();
+        assert_eq!(account.nonce, U256::one());
+        assert_eq!(account.balance, U256::from(100u64));
+        assert_eq!(account.code, vec![0x60, 0x00]);
+        assert_eq!(fork.storage(address, U256::one()).unwrap(), M256::from(42u64));
+        assert_eq!(fork.block_hash(U256::from(0x10u64)).unwrap(), H256::from(&[0x11; 32][..]));
+
+        // Everything is served from the cache the second time.
+        let requests = methods.lock().unwrap().len();
+        fork.account(address).unwrap();
+        fork.storage(address, U256::one()).unwrap();
+        fork.block_hash(U256::from(0x10u64)).unwrap();
+        assert_eq!(methods.lock().unwrap().len(), requests);
+        assert_eq!(requests, 6);
+    }
+
+    #[test]
+    fn pinned_block_is_not_requested() {
+        let (url, methods) = stub_node();
+
+        let fork = Fork::new(&url, Some(U256::from(5u64))).unwrap();
+        assert_eq!(fork.block_number(), U256::from(5u64));
+        assert!(methods.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn merge_keeps_first_block() {
+        let (url, _) = stub_node();
+        let fork = Fork::new(&url, Some(U256::zero())).unwrap();
+        let address = Address::from(&[0x11; 20][..]);
+
+        let mut changes = LocalChanges::default();
+        changes.storage.insert((address, U256::one()), 3);
+        changes.deleted_accounts.insert(address, 4);
+        fork.merge_local_changes(changes.clone());
+
+        let mut later = LocalChanges::default();
+        later.storage.insert((address, U256::one()), 7);
+        fork.merge_local_changes(later);
+
+        assert_eq!(fork.local_changes(), changes);
+        assert!(!fork.is_dirty(address, U256::one(), 2));
+        assert!(fork.is_dirty(address, U256::one(), 3));
+        assert!(fork.is_deleted(address, 4));
+
+        fork.rewind(3);
+        assert!(!fork.is_deleted(address, 4));
+    }
+}
@@ -0,0 +1,221 @@
+use bigint::{M256, U256, B256, Gas, Address};
+use hexutil::read_hex;
+use serde_json;
+use sputnikvm::AccountChange;
+use std::collections::HashMap;
+use std::fs::File;
+use std::path::Path;
+use std::rc::Rc;
+use std::str::FromStr;
+
+use error::Error;
+use super::empty_storage;
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct GenesisFile {
+    timestamp: Option<String>,
+    gas_limit: Option<String>,
+    extra_data: Option<String>,
+    difficulty: Option<String>,
+    #[serde(default)]
+    alloc: HashMap<String, GenesisFileAccount>,
+}
+
+#[derive(Deserialize)]
+struct GenesisFileAccount {
+    balance: Option<String>,
+    nonce: Option<String>,
+    code: Option<String>,
+    #[serde(default)]
+    storage: HashMap<String, String>,
+}
+
+pub struct GenesisAccount {
+    pub address: Address,
+    pub balance: U256,
+    pub nonce: U256,
+    pub code: Vec<u8>,
+    pub storage: HashMap<U256, M256>,
+}
+
+/// Genesis block settings and pre-allocated accounts, read from a file in
+/// the geth `genesis.json` format. Other fields of the file, such as
+/// `config`, are ignored.
+pub struct Genesis {
+    pub timestamp: Option<u64>,
+    pub gas_limit: Option<Gas>,
+    pub extra_data: B256,
+    pub difficulty: U256,
+    pub accounts: Vec<GenesisAccount>,
+}
+
+/// Parse a quantity that is either 0x-prefixed hex or decimal, as geth
+/// accepts both.
+fn read_u256(s: &str) -> Result<U256, Error> {
+    if s.starts_with("0x") {
+        U256::from_str(s).map_err(|_| Error::HexError(s.to_string()))
+    } else {
+        U256::from_dec_str(s).map_err(|_| Error::IntError(s.to_string()))
+    }
+}
+
+fn read_address(s: &str) -> Result<Address, Error> {
+    let bytes = if s.starts_with("0x") {
+        read_hex(s)?
+    } else {
+        read_hex(&format!("0x{}", s))?
+    };
+    if bytes.len() != 20 {
+        return Err(Error::HexError(s.to_string()));
+    }
+    Ok(Address::from(bytes.as_slice()))
+}
+
+impl Genesis {
+    pub fn from_file(path: &Path) -> Result<Genesis, Error> {
+        let file: GenesisFile = serde_json::from_reader(File::open(path)?)
+            .map_err(|err| Error::InvalidParams(format!("{}: {}", path.display(), err)))?;
+        Genesis::from_genesis_file(file)
+    }
+
+    fn from_genesis_file(file: GenesisFile) -> Result<Genesis, Error> {
+        let extra_data = match file.extra_data {
+            Some(ref extra_data) => read_hex(extra_data)?,
+            None => Vec::new(),
+        };
+        if extra_data.len() > 32 {
+            return Err(Error::InvalidParams(format!("extraData is {} bytes, at most 32 allowed", extra_data.len())));
+        }
+
+        let mut accounts = Vec::new();
+        for (address, account) in file.alloc {
+            let mut storage = HashMap::new();
+            for (index, value) in account.storage {
+                storage.insert(read_u256(&index)?, M256::from(read_u256(&value)?));
+            }
+
+            accounts.push(GenesisAccount {
+                address: read_address(&address)?,
+                balance: match account.balance {
+                    Some(ref balance) => read_u256(balance)?,
+                    None => U256::zero(),
+                },
+                nonce: match account.nonce {
+                    Some(ref nonce) => read_u256(nonce)?,
+                    None => U256::zero(),
+                },
+                code: match account.code {
+                    Some(ref code) => read_hex(code)?,
+                    None => Vec::new(),
+                },
+                storage,
+            });
+        }
+
+        Ok(Genesis {
+            timestamp: match file.timestamp {
+                Some(ref timestamp) => Some(read_u256(timestamp)?.as_u64()),
+                None => None,
+            },
+            gas_limit: match file.gas_limit {
+                Some(ref gas_limit) => Some(Gas::from(read_u256(gas_limit)?)),
+                None => None,
+            },
+            extra_data: B256::new(&extra_data),
+            difficulty: match file.difficulty {
+                Some(ref difficulty) => read_u256(difficulty)?,
+                None => U256::zero(),
+            },
+            accounts,
+        })
+    }
+
+    /// Account changes creating every pre-allocated account.
+    pub fn account_changes(&self) -> Vec<AccountChange> {
+        self.accounts.iter().map(|account| {
+            let mut storage = empty_storage(account.address);
+            for (index, value) in &account.storage {
+                storage.write(*index, *value).unwrap();
+            }
+
+            AccountChange::Create {
+                nonce: account.nonce,
+                address: account.address,
+                balance: account.balance,
+                storage,
+                code: Rc::new(account.code.clone()),
+            }
+        }).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_alloc_with_code_and_storage() {
+        let file: GenesisFile = serde_json::from_str(r#"{
+            "config": { "chainId": 61 },
+            "timestamp": "0x5a000000",
+            "gasLimit": "8000000",
+            "extraData": "0x1234",
+            "difficulty": "0x400",
+            "alloc": {
+                "0x1000000000000000000000000000000000000001": {
+                    "balance": "1000000000000000000",
+                    "nonce": "0x2",
+                    "code": "0x6001600055",
+                    "storage": {
+                        "0x00": "0x01",
+                        "0x0000000000000000000000000000000000000000000000000000000000000002": "0xff"
+                    }
+                },
+                "2000000000000000000000000000000000000002": { "balance": "0x10" }
+            }
+        }"#).unwrap();
+        let genesis = Genesis::from_genesis_file(file).unwrap();
+
+        assert_eq!(genesis.timestamp, Some(0x5a000000));
+        assert_eq!(genesis.gas_limit, Some(Gas::from(8000000u64)));
+        assert_eq!(genesis.difficulty, U256::from(0x400u64));
+        assert_eq!(genesis.accounts.len(), 2);
+
+        let contract = genesis.accounts.iter()
+            .find(|account| account.address == read_address("0x1000000000000000000000000000000000000001").unwrap())
+            .unwrap();
+        assert_eq!(contract.balance, U256::from(1000000000000000000u64));
+        assert_eq!(contract.nonce, U256::from(2u64));
+        assert_eq!(contract.code, vec![0x60, 0x01, 0x60, 0x00, 0x55]);
+        assert_eq!(contract.storage.len(), 2);
+        assert_eq!(contract.storage[&U256::zero()], M256::from(1u64));
+        assert_eq!(contract.storage[&U256::from(2u64)], M256::from(0xffu64));
+
+        let plain = genesis.accounts.iter()
+            .find(|account| account.address == read_address("0x2000000000000000000000000000000000000002").unwrap())
+            .unwrap();
+        assert_eq!(plain.balance, U256::from(0x10u64));
+        assert!(plain.code.is_empty() && plain.storage.is_empty());
+
+        for change in genesis.account_changes() {
+            match change {
+                AccountChange::Create { address, storage, .. } => {
+                    let storage: HashMap<U256, M256> = storage.into();
+                    if address == contract.address {
+                        assert_eq!(storage, contract.storage);
+                    } else {
+                        assert!(storage.is_empty());
+                    }
+                },
+                _ => panic!("expected a create change"),
+            }
+        }
+    }
+
+    #[test]
+    fn reject_long_extra_data() {
+        let file: GenesisFile = serde_json::from_str(&format!(r#"{{ "extraData": "0x{}" }}"#, "00".repeat(33))).unwrap();
+        assert!(Genesis::from_genesis_file(file).is_err());
+    }
+}
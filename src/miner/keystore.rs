@@ -0,0 +1,286 @@
+use aes_ctr::Aes128Ctr;
+use aes_ctr::cipher::stream::{NewStreamCipher, SyncStreamCipher};
+use bigint::Address;
+use block::FromKey;
+use hexutil::{read_hex, to_hex};
+use hmac::Hmac;
+use pbkdf2::pbkdf2;
+use rand::Rng;
+use rand::os::OsRng;
+use scrypt::{scrypt, ScryptParams};
+use secp256k1::SECP256K1;
+use secp256k1::key::SecretKey;
+use serde_json;
+use sha2::Sha256;
+use sha3::{Digest, Keccak256};
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::path::PathBuf;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use error::Error;
+
+#[derive(Serialize, Deserialize, Clone)]
+struct CipherParams {
+    iv: String,
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+struct KdfParams {
+    dklen: usize,
+    salt: String,
+    // scrypt
+    n: Option<u64>,
+    r: Option<u32>,
+    p: Option<u32>,
+    // pbkdf2
+    c: Option<usize>,
+    prf: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+struct Crypto {
+    cipher: String,
+    ciphertext: String,
+    cipherparams: CipherParams,
+    kdf: String,
+    kdfparams: KdfParams,
+    mac: String,
+}
+
+/// A key file in the Web3 Secret Storage V3 format.
+#[derive(Serialize, Deserialize, Clone)]
+pub struct KeyFile {
+    address: String,
+    crypto: Crypto,
+    id: String,
+    version: u32,
+}
+
+// The scrypt parameters of geth's `--lightkdf`, as svmdev keys only guard
+// test accounts.
+const SCRYPT_LOG_N: u8 = 12;
+const SCRYPT_R: u32 = 8;
+const SCRYPT_P: u32 = 6;
+
+fn random_bytes(len: usize) -> Result<Vec<u8>, Error> {
+    let mut rng = OsRng::new().map_err(|err| Error::InternalError(format!("{}", err)))?;
+    Ok((0..len).map(|_| rng.gen::<u8>()).collect())
+}
+
+fn hex(bytes: &[u8]) -> String {
+    to_hex(bytes).trim_start_matches("0x").to_string()
+}
+
+fn unhex(s: &str) -> Result<Vec<u8>, Error> {
+    Ok(read_hex(&format!("0x{}", s.trim_start_matches("0x")))?)
+}
+
+fn missing_param(name: &str) -> Error {
+    Error::InvalidParams(format!("missing kdf param {}", name))
+}
+
+fn derive_key(password: &str, params: &KdfParams, kdf: &str) -> Result<Vec<u8>, Error> {
+    let salt = unhex(&params.salt)?;
+    let mut key = vec![0u8; params.dklen];
+
+    match kdf {
+        "scrypt" => {
+            let n = params.n.ok_or_else(|| missing_param("n"))?;
+            let log_n = (63 - n.leading_zeros()) as u8;
+            let scrypt_params = ScryptParams::new(
+                log_n, params.r.ok_or_else(|| missing_param("r"))?, params.p.ok_or_else(|| missing_param("p"))?
+            ).map_err(|err| Error::InvalidParams(format!("scrypt params: {:?}", err)))?;
+            scrypt(password.as_bytes(), &salt, &scrypt_params, &mut key)
+                .map_err(|err| Error::InvalidParams(format!("scrypt: {:?}", err)))?;
+        },
+        "pbkdf2" => {
+            if params.prf.as_ref().map(|prf| &prf[..]) != Some("hmac-sha256") {
+                return Err(Error::InvalidParams("unsupported pbkdf2 prf".to_string()));
+            }
+            pbkdf2::<Hmac<Sha256>>(password.as_bytes(), &salt, params.c.ok_or_else(|| missing_param("c"))?, &mut key);
+        },
+        _ => return Err(Error::InvalidParams(format!("unsupported kdf {}", kdf))),
+    }
+
+    Ok(key)
+}
+
+fn mac(derived_key: &[u8], ciphertext: &[u8]) -> Vec<u8> {
+    let mut data = derived_key[16..32].to_vec();
+    data.extend_from_slice(ciphertext);
+    Keccak256::digest(&data).as_slice().to_vec()
+}
+
+fn apply_cipher(derived_key: &[u8], iv: &[u8], data: &mut [u8]) -> Result<(), Error> {
+    if iv.len() != 16 {
+        return Err(Error::InvalidParams(format!("iv must be 16 bytes, got {}", iv.len())));
+    }
+    let mut cipher = Aes128Ctr::new_var(&derived_key[0..16], iv)
+        .map_err(|_| Error::InvalidParams("invalid cipher key or iv".to_string()))?;
+    cipher.apply_keystream(data);
+    Ok(())
+}
+
+impl KeyFile {
+    pub fn encrypt(secret_key: &SecretKey, password: &str) -> Result<KeyFile, Error> {
+        let params = KdfParams {
+            dklen: 32,
+            salt: hex(&random_bytes(32)?),
+            n: Some(1 << SCRYPT_LOG_N),
+            r: Some(SCRYPT_R),
+            p: Some(SCRYPT_P),
+            c: None,
+            prf: None,
+        };
+        let derived_key = derive_key(password, &params, "scrypt")?;
+
+        let iv = random_bytes(16)?;
+        let mut ciphertext = secret_key[..].to_vec();
+        apply_cipher(&derived_key, &iv, &mut ciphertext)?;
+
+        let id = random_bytes(16)?;
+        let address = Address::from_secret_key(secret_key)?;
+
+        Ok(KeyFile {
+            address: format!("{:x}", address),
+            crypto: Crypto {
+                cipher: "aes-128-ctr".to_string(),
+                ciphertext: hex(&ciphertext),
+                cipherparams: CipherParams { iv: hex(&iv) },
+                kdf: "scrypt".to_string(),
+                kdfparams: params,
+                mac: hex(&mac(&derived_key, &ciphertext)),
+            },
+            // A random (version 4) UUID.
+            id: format!("{}-{}-4{}-{:x}{}-{}",
+                        hex(&id[0..4]), hex(&id[4..6]), &hex(&id[6..8])[1..],
+                        8 | (id[8] & 0x3), &hex(&id[8..10])[1..], hex(&id[10..16])),
+            version: 3,
+        })
+    }
+
+    pub fn decrypt(&self, password: &str) -> Result<SecretKey, Error> {
+        if self.crypto.cipher != "aes-128-ctr" {
+            return Err(Error::InvalidParams(format!("unsupported cipher {}", self.crypto.cipher)));
+        }
+
+        let derived_key = derive_key(password, &self.crypto.kdfparams, &self.crypto.kdf)?;
+        let mut data = unhex(&self.crypto.ciphertext)?;
+        if mac(&derived_key, &data) != unhex(&self.crypto.mac)? {
+            return Err(Error::InvalidPassword);
+        }
+
+        apply_cipher(&derived_key, &unhex(&self.crypto.cipherparams.iv)?, &mut data)?;
+        Ok(SecretKey::from_slice(&SECP256K1, &data)?)
+    }
+
+    pub fn address(&self) -> Result<Address, Error> {
+        let bytes = unhex(&self.address)?;
+        if bytes.len() != 20 {
+            return Err(Error::HexError(self.address.clone()));
+        }
+        Ok(Address::from(bytes.as_slice()))
+    }
+}
+
+/// Encrypted account keys, stored as key files in a directory, or only in
+/// memory if no directory is given. Keys of the accounts generated at
+/// startup are kept as is, with an empty password.
+pub struct Keystore {
+    dir: Option<PathBuf>,
+    files: HashMap<Address, KeyFile>,
+    generated: HashMap<Address, SecretKey>,
+}
+
+impl Keystore {
+    pub fn new() -> Keystore {
+        Keystore {
+            dir: None,
+            files: HashMap::new(),
+            generated: HashMap::new(),
+        }
+    }
+
+    /// Use the directory, creating it if needed, and load the key files
+    /// already in it. Returns the addresses of the loaded keys.
+    pub fn open(&mut self, dir: PathBuf) -> Result<Vec<Address>, Error> {
+        fs::create_dir_all(&dir)?;
+
+        let mut addresses = Vec::new();
+        for entry in fs::read_dir(&dir)? {
+            let path = entry?.path();
+            if !path.is_file() {
+                continue;
+            }
+
+            let file: KeyFile = match serde_json::from_reader(File::open(&path)?) {
+                Ok(file) => file,
+                Err(_) => {
+                    warn!("Ignored invalid key file {:?}", path);
+                    continue;
+                },
+            };
+            let address = file.address()?;
+            if !self.files.contains_key(&address) {
+                addresses.push(address);
+            }
+            self.files.insert(address, file);
+        }
+
+        self.dir = Some(dir);
+        Ok(addresses)
+    }
+
+    pub fn contains(&self, address: Address) -> bool {
+        self.files.contains_key(&address) || self.generated.contains_key(&address)
+    }
+
+    pub fn insert_generated(&mut self, secret_key: SecretKey) -> Result<Address, Error> {
+        let address = Address::from_secret_key(&secret_key)?;
+        self.generated.insert(address, secret_key);
+        Ok(address)
+    }
+
+    /// Encrypt and store a key, returning its address.
+    pub fn insert(&mut self, secret_key: &SecretKey, password: &str) -> Result<Address, Error> {
+        let file = KeyFile::encrypt(secret_key, password)?;
+        let address = file.address()?;
+
+        if let Some(ref dir) = self.dir {
+            let timestamp = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs();
+            let path = dir.join(format!("UTC--{}--{:x}", timestamp, address));
+            serde_json::to_writer(File::create(path)?, &file).map_err(|err| Error::IOError(format!("{}", err)))?;
+        }
+        self.files.insert(address, file);
+
+        Ok(address)
+    }
+
+    pub fn decrypt(&self, address: Address, password: &str) -> Result<SecretKey, Error> {
+        if let Some(file) = self.files.get(&address) {
+            return file.decrypt(password);
+        }
+
+        match self.generated.get(&address) {
+            Some(secret_key) if password.is_empty() => Ok(secret_key.clone()),
+            Some(_) => Err(Error::InvalidPassword),
+            None => Err(Error::UnknownAccount(address)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn encrypt_then_decrypt() {
+        let secret_key = SecretKey::from_slice(&SECP256K1, &[1u8; 32]).unwrap();
+        let file = KeyFile::encrypt(&secret_key, "password").unwrap();
+
+        assert_eq!(file.address().unwrap(), Address::from_secret_key(&secret_key).unwrap());
+        assert_eq!(file.decrypt("password").unwrap(), secret_key);
+        assert!(file.decrypt("wrong").is_err());
+    }
+}
@@ -1,5 +1,5 @@
-use rlp;
-use block::{Receipt, Block, UnsignedTransaction, Transaction, TransactionAction, Log, FromKey, Header, Account, ommers_hash, transactions_root, receipts_root, RlpHash};
+use rlp::{self, UntrustedRlp};
+use block::{Receipt, Block, UnsignedTransaction, Transaction, TransactionAction, TransactionSignature, Log, FromKey, Header, Account, ommers_hash, transactions_root, receipts_root, RlpHash};
 use trie::{MemoryDatabase, Database, MemoryDatabaseGuard, Trie};
 use bigint::{H256, M256, U256, H64, B256, Gas, Address};
 use bloom::LogsBloom;
@@ -8,30 +8,130 @@ use secp256k1::key::{PublicKey, SecretKey};
 use std::time::Duration;
 use std::thread;
 use std::str::FromStr;
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use std::time::{SystemTime, UNIX_EPOCH};
 use std::sync::{Arc, Mutex};
-use std::sync::mpsc::{channel, Sender, Receiver};
+use std::sync::mpsc::{Receiver, RecvTimeoutError};
 use std::rc::Rc;
-use sputnikvm::{AccountChange, ValidTransaction, Patch, AccountCommitment, AccountState, HeaderParams, SeqTransactionVM, VM, VMStatus};
-use sputnikvm::errors::RequireError;
-use sputnikvm_stateful::MemoryStateful;
+use std::path::Path;
+use std::fs::File;
+use std::io::{Read, Write, BufReader, BufWriter};
+use std::fmt;
+use sputnikvm::{AccountChange, ValidTransaction, UntrustedTransaction, Patch, AccountCommitment, AccountState, EmbeddedAccountPatch, Storage, HeaderParams, SeqTransactionVM, VM, VMStatus};
+use sputnikvm::errors::{RequireError, PreExecutionError, OnChainError};
 use rand::os::OsRng;
 use sha3::{Digest, Keccak256};
 use blockchain::chain::HeaderHash;
 use hexutil::*;
+use error::Error;
 
 mod state;
+mod pool;
+mod database;
+mod datadir;
+mod genesis;
+mod fork;
+mod keystore;
 
 pub use self::state::MinerState;
+pub use self::database::{ChainDatabase, ChainStateful};
+pub use self::genesis::{Genesis, GenesisAccount};
+pub use self::fork::{Fork, AccountInfo};
+use self::datadir::{DataDir, Record};
+pub use self::datadir::has_chain;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MiningMode {
+    /// Mine one block for every transaction, before the RPC call returns.
+    Automine,
+    /// Mine one block every given number of milliseconds.
+    Interval(u64),
+    /// Only mine when explicitly requested through RPC.
+    Manual,
+}
+
+impl FromStr for MiningMode {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<MiningMode, Error> {
+        match s {
+            "auto" => Ok(MiningMode::Automine),
+            "manual" => Ok(MiningMode::Manual),
+            _ => {
+                let interval: u64 = s.parse()?;
+                if interval == 0 {
+                    return Err(Error::InvalidParams("mining interval must not be 0".to_string()));
+                }
+                Ok(MiningMode::Interval(interval))
+            },
+        }
+    }
+}
+
+impl fmt::Display for MiningMode {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            &MiningMode::Automine => write!(f, "auto"),
+            &MiningMode::Interval(interval) => write!(f, "{}", interval),
+            &MiningMode::Manual => write!(f, "manual"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub enum BlockReward {
+    /// The same reward for every block.
+    Fixed(U256),
+    /// ECIP-1017 monetary policy, where the base reward is reduced by 20%
+    /// every `era_length` blocks.
+    Era { base: U256, era_length: usize },
+}
+
+impl BlockReward {
+    pub fn at(&self, number: U256) -> U256 {
+        match self {
+            &BlockReward::Fixed(reward) => reward,
+            &BlockReward::Era { base, era_length } => {
+                if number == U256::zero() {
+                    return base;
+                }
+
+                let era = (number.as_usize() - 1) / era_length;
+                let mut numerator = base;
+                let mut denominator = U256::one();
+                for _ in 0..era {
+                    numerator = numerator * U256::from(4u64);
+                    denominator = denominator * U256::from(5u64);
+                }
+                numerator / denominator
+            },
+        }
+    }
+}
+
+/// Empty full storage of an account, to build an `AccountChange::Create`
+/// with. `Storage::new` is private to sputnikvm, so it is taken from an
+/// account created in an `AccountState`.
+pub fn empty_storage(address: Address) -> Storage {
+    let mut account_state: AccountState<EmbeddedAccountPatch> = AccountState::default();
+    account_state.commit(AccountCommitment::Nonexist(address)).unwrap();
+    account_state.set_nonce(address, U256::zero()).unwrap();
+
+    match account_state.accounts().next() {
+        Some(&AccountChange::Create { ref storage, .. }) => storage.clone(),
+        _ => unreachable!(),
+    }
+}
+
+pub fn ether(value: u64) -> U256 {
+    U256::from(value) * U256::from(1000000000000000000u64)
+}
 
 fn next<'a>(
     state: &mut MinerState,
     current_block: &Block, transactions: &[Transaction], receipts: &[Receipt],
-    beneficiary: Address, gas_limit: Gas, state_root: H256,
+    header: &HeaderParams, state_root: H256,
 ) -> Block {
-    // TODO: Handle block rewards.
-
     debug_assert!(transactions.len() == receipts.len());
 
     let mut logs_bloom = LogsBloom::new();
@@ -47,16 +147,16 @@ fn next<'a>(
     let header = Header {
         parent_hash: current_block.header.header_hash(),
         ommers_hash: ommers_hash(&[]),
-        beneficiary,
+        beneficiary: header.beneficiary,
         state_root: state_root,
         transactions_root: transactions_root(transactions),
         receipts_root: receipts_root(receipts),
         logs_bloom,
-        gas_limit,
+        gas_limit: header.gas_limit,
         gas_used,
-        timestamp: current_timestamp(),
+        timestamp: header.timestamp,
         extra_data: B256::default(),
-        number: current_block.header.number + U256::one(),
+        number: header.number,
 
         difficulty: U256::zero(),
         mix_hash: H256::default(),
@@ -75,11 +175,66 @@ fn current_timestamp() -> u64 {
 }
 
 lazy_static! {
-    static ref DATABASE: MemoryDatabase = MemoryDatabase::default();
+    static ref DATABASE: ChainDatabase = ChainDatabase::new();
+}
+
+fn print_account(secret_key: &SecretKey) {
+    let address = Address::from_secret_key(secret_key).unwrap();
+    println!("address: {:?}", address);
+    println!("private key: {}", to_hex(&secret_key[..]));
 }
 
-pub fn make_state<P: Patch>(genesis_accounts: Vec<(SecretKey, U256)>) -> MinerState {
-    let mut stateful = MemoryStateful::empty(&DATABASE);
+/// Create the chain state. With a data directory that already holds a
+/// chain, it is resumed and the genesis accounts are ignored. Otherwise a
+/// new chain is created from the genesis accounts and the optional genesis
+/// file, and written to the data directory if given.
+pub fn make_state<P: Patch>(
+    genesis_accounts: Vec<(SecretKey, U256)>, gas_limit: Gas, genesis_file: Option<Genesis>,
+    fork: Option<(&str, Option<U256>)>, datadir: Option<&Path>
+) -> MinerState {
+    let (datadir, records) = match datadir {
+        Some(path) => {
+            let (datadir, records) = DataDir::open(path).unwrap();
+            DATABASE.open(&path.join("state")).unwrap();
+            (Some(datadir), records)
+        },
+        None => (None, Vec::new()),
+    };
+
+    if records.len() > 0 {
+        // A forked chain is resumed from the block it was forked from,
+        // which its genesis block stands for.
+        let genesis_number = match records[0] {
+            Record::Block(ref genesis) => genesis.header.number,
+            _ => U256::zero(),
+        };
+        let fork = fork.map(|(url, block)| {
+            if let Some(block) = block {
+                if block != genesis_number {
+                    panic!("the chain in the data directory was forked from block {}, not {}", genesis_number, block);
+                }
+            }
+            Fork::new(url, Some(genesis_number)).unwrap()
+        });
+
+        let state = MinerState::restore(&DATABASE, datadir.unwrap(), records, fork).unwrap();
+        if let Some(fork) = state.fork() {
+            println!("forked from block: {}", fork.block_number());
+        }
+        println!("resumed at block: {}", state.block_height());
+        for address in state.accounts() {
+            print_account(&state.secret_key(address).unwrap());
+        }
+        return state;
+    }
+
+    let fork = fork.map(|(url, block)| {
+        let fork = Fork::new(url, block).unwrap();
+        println!("forked from block: {}", fork.block_number());
+        fork
+    });
+
+    let mut stateful = ChainStateful::empty(&DATABASE);
     let mut genesis = Block {
         header: Header {
             parent_hash: H256::default(),
@@ -91,7 +246,7 @@ pub fn make_state<P: Patch>(genesis_accounts: Vec<(SecretKey, U256)>) -> MinerSt
             receipts_root: MemoryDatabase::default().create_empty().root(),
             logs_bloom: LogsBloom::new(),
             number: U256::zero(),
-            gas_limit: Gas::zero(),
+            gas_limit,
             gas_used: Gas::zero(),
             timestamp: current_timestamp(),
             extra_data: B256::default(),
@@ -104,10 +259,39 @@ pub fn make_state<P: Patch>(genesis_accounts: Vec<(SecretKey, U256)>) -> MinerSt
         ommers: Vec::new(),
     };
 
+    // In fork mode, the genesis block stands for the pinned upstream block.
+    if let Some(ref fork) = fork {
+        let number = fork.block_number();
+        genesis.header.number = number;
+        if number > U256::zero() {
+            genesis.header.parent_hash = fork.block_hash(number - U256::one()).unwrap();
+        }
+    }
+
     let mut all_account_changes = Vec::new();
+
+    if let Some(genesis_file) = genesis_file {
+        if let Some(timestamp) = genesis_file.timestamp {
+            genesis.header.timestamp = timestamp;
+        }
+        genesis.header.extra_data = genesis_file.extra_data.clone();
+        genesis.header.difficulty = genesis_file.difficulty;
+
+        let accounts = genesis_file.account_changes();
+        stateful.transit(&accounts);
+        all_account_changes.push(accounts);
+    }
+
     for &(ref secret_key, balance) in &genesis_accounts {
         let address = Address::from_secret_key(secret_key).unwrap();
 
+        // Accounts already in the genesis file or loaded state keep their
+        // balance.
+        let existing: Option<Account> = stateful.state_of(stateful.root()).get(&address);
+        if existing.is_some() {
+            continue;
+        }
+
         let vm: SeqTransactionVM<P> = {
             let vm = stateful.call(ValidTransaction {
                 caller: None,
@@ -130,91 +314,628 @@ pub fn make_state<P: Patch>(genesis_accounts: Vec<(SecretKey, U256)>) -> MinerSt
 
     genesis.header.state_root = stateful.root();
 
-    let mut state = MinerState::new(genesis, stateful);
+    let mut state = MinerState::new(genesis, stateful, datadir);
+    if let Some(fork) = fork {
+        state.set_fork(fork);
+    }
 
     for (secret_key, balance) in genesis_accounts {
-        let address = Address::from_secret_key(&secret_key).unwrap();
-        println!("address: {:?}", address);
-        println!("private key: {}", to_hex(&secret_key[..]));
+        print_account(&secret_key);
 
         state.append_account(secret_key);
-        for accounts in &all_account_changes {
-            state.fat_transit(0, &accounts);
-        }
+    }
+    let number = state.genesis_number();
+    for accounts in &all_account_changes {
+        state.fat_transit(number, &accounts).unwrap();
     }
 
     state
 }
 
+/// Drive interval mining. Automine blocks are mined by the RPC thread
+/// itself, and manual blocks on request, so in those modes the loop only
+/// waits for the mining mode to change. Any message on the channel means
+/// the mode has been changed.
 pub fn mine_loop<P: Patch>(state: Arc<Mutex<MinerState>>, channel: Receiver<bool>) {
     loop {
-        mine_one::<P>(state.clone(), Address::default());
+        let mode = state.lock().unwrap().mining_mode();
+
+        let mode_changed = match mode {
+            MiningMode::Interval(interval) => {
+                match channel.recv_timeout(Duration::from_millis(interval)) {
+                    Ok(_) => true,
+                    Err(RecvTimeoutError::Timeout) => false,
+                    Err(RecvTimeoutError::Disconnected) => return,
+                }
+            },
+            MiningMode::Automine | MiningMode::Manual => {
+                match channel.recv() {
+                    Ok(_) => true,
+                    Err(_) => return,
+                }
+            },
+        };
+
+        if !mode_changed {
+            mine_one::<P>(&mut state.lock().unwrap());
+        }
+    }
+}
 
-        channel.recv_timeout(Duration::new(10, 0));
+pub fn mine_one<P: Patch>(state: &mut MinerState) {
+    let pending_transactions = state.pending_transactions();
+    mine_block::<P>(state, pending_transactions);
+}
+
+/// Mine a block with only the given transaction, for automine. Nothing is
+/// mined while it is queued behind a nonce gap. Once it is mined, the
+/// transactions of the same sender it unblocked are mined after it, one
+/// block each.
+pub fn mine_transaction<P: Patch>(state: &mut MinerState, sender: Address, hash: H256) {
+    let mut next = match next_pending_transaction(state, sender) {
+        Some(transaction) if transaction.rlp_hash() == hash => Some(transaction),
+        _ => None,
+    };
+
+    while let Some(transaction) = next {
+        if mine_block::<P>(state, vec![(sender, transaction)]) == 0 {
+            break;
+        }
+        next = next_pending_transaction(state, sender);
     }
 }
 
-pub fn mine_one<P: Patch>(state: Arc<Mutex<MinerState>>, address: Address) {
-    let mut state = state.lock().unwrap();
+fn next_pending_transaction(state: &MinerState, sender: Address) -> Option<Transaction> {
+    state.pending_transactions().into_iter()
+        .find(|&(address, _)| address == sender)
+        .map(|(_, transaction)| transaction)
+}
 
+/// Mine a block with as many of the given transactions as fit, returning
+/// how many were included.
+fn mine_block<P: Patch>(state: &mut MinerState, pending_transactions: Vec<(Address, Transaction)>) -> usize {
     let current_block = state.current_block();
-    let transactions = state.clear_pending_transactions();
     let block_hashes = state.get_last_256_block_hashes();
 
-    let beneficiary = address;
+    let number = current_block.header.number.as_usize() + 1;
+    // Transaction fees are credited to the header's beneficiary by the VM.
+    let header = HeaderParams {
+        beneficiary: state.coinbase(),
+        timestamp: state.next_block_timestamp(),
+        number: U256::from(number),
+        difficulty: U256::zero(),
+        gas_limit: state.block_gas_limit(),
+    };
 
+    let mut transactions = Vec::new();
     let mut receipts = Vec::new();
+    let mut block_gas_used = Gas::zero();
+
+    // The block being mined is above the current one, so its storage
+    // table always exists.
+    state.fat_transit(number, &[]).unwrap();
 
-    state.fat_transit(current_block.header.number.as_usize(), &[]);
+    let mut dropped_senders = HashSet::new();
+
+    for (sender, transaction) in pending_transactions {
+        if block_gas_used + transaction.gas_limit > header.gas_limit {
+            break;
+        }
+
+        // Once a transaction is dropped, later nonces of the same sender
+        // have a gap, so they stay in the pool as queued.
+        if dropped_senders.contains(&sender) {
+            continue;
+        }
 
-    for transaction in transactions.clone() {
         let transaction_hash = transaction.rlp_hash();
-        let valid = state.stateful_mut().to_valid::<P>(transaction).unwrap();
-        let vm: SeqTransactionVM<P> = {
-            let vm = state.stateful_mut().call(valid, HeaderParams::from(&current_block.header),
-                               &block_hashes);
-            let mut accounts = Vec::new();
-            for account in vm.accounts() {
-                accounts.push(account.clone());
+        if let Some(fork) = state.fork() {
+            if let Err(err) = fork.materialize(state.stateful_mut(), sender, number) {
+                warn!("Failed to fetch upstream account 0x{:x}: {:?}", sender, err);
+                dropped_senders.insert(sender);
+                continue;
             }
-            state.stateful_mut().transit(&accounts);
-            state.fat_transit(current_block.header.number.as_usize(), &accounts);
-            vm
+        }
+        let valid = match to_valid::<P>(state.stateful(), state.unsigned_senders(), transaction.clone()) {
+            Ok(valid) => valid,
+            Err(err) => {
+                state.drop_pending_transaction(sender, &transaction, format!("{:?}", err));
+                dropped_senders.insert(sender);
+                continue;
+            },
         };
+        let receipt = execute::<P>(state, transaction_hash, valid, &header, &block_hashes);
+        block_gas_used = block_gas_used + receipt.used_gas;
 
-        let logs: Vec<Log> = vm.logs().into();
-        let used_gas = vm.used_gas();
-        let mut logs_bloom = LogsBloom::new();
-        for log in logs.clone() {
-            logs_bloom.set(&log.address);
-            for topic in log.topics {
-                logs_bloom.set(&topic)
-            }
+        receipts.push(receipt);
+        transactions.push(transaction);
+
+        println!("0x{:x}", transaction_hash);
+    }
+
+    let included = transactions.len();
+    let next_block = seal(state, &current_block, transactions, receipts, &header);
+    debug!("block number: 0x{:x}", next_block.header.number);
+    state.append_block(next_block);
+    state.prune_pending_transactions();
+    included
+}
+
+/// A transaction sent from an impersonated account. It cannot be signed,
+/// so it is left with an empty signature, and `MinerState` keeps its
+/// sender next to it.
+pub fn unsigned_transaction(unsigned: UnsignedTransaction) -> Transaction {
+    Transaction {
+        nonce: unsigned.nonce,
+        gas_price: unsigned.gas_price,
+        gas_limit: unsigned.gas_limit,
+        action: unsigned.action,
+        value: unsigned.value,
+        signature: TransactionSignature {
+            v: 0,
+            r: H256::default(),
+            s: H256::default(),
+        },
+        input: unsigned.input,
+    }
+}
+
+/// Sender of a transaction, either kept in `unsigned_senders` for an
+/// unsigned transaction or recovered from its signature.
+pub fn transaction_sender(unsigned_senders: &HashMap<H256, Address>, transaction: &Transaction) -> Result<Address, Error> {
+    match unsigned_senders.get(&transaction.rlp_hash()) {
+        Some(sender) => Ok(*sender),
+        None => transaction.caller().map_err(|_| Error::ECDSAError),
+    }
+}
+
+/// `Stateful::to_valid`, also accepting the unsigned transactions whose
+/// sender is kept in `unsigned_senders`.
+pub fn to_valid<P: Patch>(
+    stateful: &ChainStateful, unsigned_senders: &HashMap<H256, Address>, transaction: Transaction
+) -> Result<ValidTransaction, Error> {
+    let sender = match unsigned_senders.get(&transaction.rlp_hash()) {
+        Some(sender) => *sender,
+        None => return Ok(stateful.to_valid::<P>(transaction)?),
+    };
+
+    let caller = match account_commitment(stateful, sender) {
+        AccountCommitment::Nonexist(address) => AccountCommitment::Full {
+            nonce: U256::zero(),
+            address,
+            balance: U256::zero(),
+            code: Rc::new(Vec::new()),
+        },
+        caller => caller,
+    };
+    match caller {
+        AccountCommitment::Full { nonce, .. } if nonce != transaction.nonce =>
+            return Err(PreExecutionError::InvalidNonce.into()),
+        _ => (),
+    }
+
+    let untrusted = UntrustedTransaction {
+        caller,
+        gas_price: transaction.gas_price,
+        gas_limit: transaction.gas_limit,
+        action: transaction.action,
+        value: transaction.value,
+        input: Rc::new(transaction.input),
+    };
+    Ok(untrusted.to_valid::<P>()?)
+}
+
+fn account_commitment(stateful: &ChainStateful, address: Address) -> AccountCommitment {
+    let account: Option<Account> = stateful.state_of(stateful.root()).get(&address);
+    match account {
+        Some(account) => AccountCommitment::Full {
+            nonce: account.nonce,
+            address,
+            balance: account.balance,
+            code: Rc::new(stateful.code(account.code_hash).unwrap_or(Vec::new())),
+        },
+        None => AccountCommitment::Nonexist(address),
+    }
+}
+
+/// Execute a transaction against the given state. Without a fork this is
+/// `Stateful::call`. In fork mode, accounts missing locally are first
+/// copied from the upstream node, storage never written locally is read
+/// from it, and block hashes before the pinned block are resolved by it.
+pub fn call<P: Patch>(
+    stateful: &mut ChainStateful, fork: &Option<Arc<Fork>>, transaction: ValidTransaction,
+    header: &HeaderParams, block_hashes: &[H256]
+) -> SeqTransactionVM<P> {
+    let fork = match fork {
+        &Some(ref fork) => fork,
+        &None => return stateful.call(transaction, header.clone(), block_hashes),
+    };
+
+    let number = header.number.as_usize();
+    let mut vm = SeqTransactionVM::<P>::new(transaction, header.clone());
+    loop {
+        match vm.fire() {
+            Ok(()) => break,
+            Err(RequireError::Account(address)) => {
+                if let Err(err) = fork.materialize(stateful, address, number) {
+                    warn!("Failed to fetch upstream account 0x{:x}: {:?}", address, err);
+                }
+                vm.commit_account(account_commitment(stateful, address)).unwrap();
+            },
+            Err(RequireError::AccountCode(address)) => {
+                if let Err(err) = fork.materialize(stateful, address, number) {
+                    warn!("Failed to fetch upstream account 0x{:x}: {:?}", address, err);
+                }
+                let code = match account_commitment(stateful, address) {
+                    AccountCommitment::Full { code, .. } => code,
+                    _ => Rc::new(Vec::new()),
+                };
+                vm.commit_account(AccountCommitment::Code { address, code }).unwrap();
+            },
+            Err(RequireError::AccountStorage(address, index)) => {
+                let root = stateful.root();
+                let value = state::storage_at(stateful, Some(&**fork), root, number, address, index)
+                    .unwrap_or_else(|err| {
+                        warn!("Failed to fetch upstream storage 0x{:x}: {:?}", address, err);
+                        M256::zero()
+                    });
+                vm.commit_account(AccountCommitment::Storage { address, index, value }).unwrap();
+            },
+            Err(RequireError::Blockhash(number)) => {
+                let hash = if number >= header.number {
+                    H256::default()
+                } else if number <= fork.block_number() {
+                    // The genesis block stands for the pinned block, but
+                    // only the upstream node has its hash.
+                    fork.block_hash(number).unwrap_or(H256::default())
+                } else {
+                    // The most recent hash comes first.
+                    let distance = (header.number - number).as_usize();
+                    if distance <= block_hashes.len() {
+                        block_hashes[distance - 1]
+                    } else {
+                        H256::default()
+                    }
+                };
+                vm.commit_blockhash(number, hash).unwrap();
+            },
         }
+    }
+    vm
+}
 
-        let receipt = Receipt {
-            used_gas: used_gas.clone(),
-            logs,
-            logs_bloom: logs_bloom.clone(),
-            state_root: state.stateful_mut().root(),
-        };
+/// Run a transaction on top of the current state and record its status,
+/// returning the receipt.
+fn execute<P: Patch>(
+    state: &mut MinerState, transaction_hash: H256, transaction: ValidTransaction,
+    header: &HeaderParams, block_hashes: &[H256]
+) -> Receipt {
+    let fork = state.fork();
+    let vm: SeqTransactionVM<P> = {
+        let vm = call::<P>(state.stateful_mut(), &fork, transaction, header, block_hashes);
+        let mut accounts = Vec::new();
+        for account in vm.accounts() {
+            accounts.push(account.clone());
+        }
+        state.mark_dirty(header.number.as_usize(), &accounts);
+        state.stateful_mut().transit(&accounts);
+        state.fat_transit(header.number.as_usize(), &accounts).unwrap();
+        vm
+    };
+
+    let logs: Vec<Log> = vm.logs().into();
+    let used_gas = vm.used_gas();
+    let mut logs_bloom = LogsBloom::new();
+    for log in logs.clone() {
+        logs_bloom.set(&log.address);
+        for topic in log.topics {
+            logs_bloom.set(&topic)
+        }
+    }
+
+    match vm.status() {
+        VMStatus::ExitedOk => state.set_receipt_status(transaction_hash, true),
+        VMStatus::ExitedErr(OnChainError::Revert) => {
+            state.set_receipt_status(transaction_hash, false);
+            state.set_revert_output(transaction_hash, vm.out().into());
+        },
+        _ => state.set_receipt_status(transaction_hash, false),
+    }
+
+    Receipt {
+        used_gas: used_gas.clone(),
+        logs,
+        logs_bloom: logs_bloom.clone(),
+        state_root: state.stateful_mut().root(),
+    }
+}
+
+/// Credit the block reward and build the block on top of `current_block`.
+fn seal(
+    state: &mut MinerState, current_block: &Block,
+    transactions: Vec<Transaction>, receipts: Vec<Receipt>, header: &HeaderParams,
+) -> Block {
+    let number = header.number.as_usize();
+    let reward = [AccountChange::IncreaseBalance(
+        header.beneficiary, state.block_reward().at(header.number))];
+    state.stateful_mut().transit(&reward);
+    state.fat_transit(number, &reward).unwrap();
+
+    let root = state.stateful_mut().root();
+    next(state, current_block, transactions.as_ref(), receipts.as_ref(), header, root)
+}
+
+/// Re-execute a block on top of the current chain and append it, checking
+/// that the resulting state root, receipts root and gas used match its
+/// header. On mismatch the block is discarded, along with everything its
+/// execution recorded.
+pub fn import_block<P: Patch>(state: &mut MinerState, block: Block) -> Result<(), Error> {
+    let current_block = state.current_block();
+    if block.header.number != current_block.header.number + U256::one() {
+        return Err(Error::InvalidBlockNumber);
+    }
+    if block.header.parent_hash != current_block.header.header_hash() {
+        return Err(Error::ParentHashMismatch);
+    }
+
+    match replay_block::<P>(state, &current_block, &block) {
+        Ok(()) => {
+            state.append_block(block);
+            Ok(())
+        },
+        Err(err) => {
+            for transaction in &block.transactions {
+                state.discard_transaction_results(transaction.rlp_hash());
+            }
+            state.rewind(current_block.header.number.as_usize())?;
+            Err(err)
+        },
+    }
+}
+
+/// Execute the transactions of a block on top of `current_block` and
+/// check the result against its header. The state is left changed either
+/// way.
+fn replay_block<P: Patch>(state: &mut MinerState, current_block: &Block, block: &Block) -> Result<(), Error> {
+    let block_hashes = state.get_last_256_block_hashes();
+    let header = HeaderParams::from(&block.header);
+    let number = header.number.as_usize();
+
+    let mut receipts = Vec::new();
+    state.fat_transit(number, &[])?;
+
+    for transaction in &block.transactions {
+        let caller = state.transaction_sender(transaction)?;
+        if let Some(fork) = state.fork() {
+            fork.materialize(state.stateful_mut(), caller, number)?;
+        }
+        let valid = to_valid::<P>(state.stateful(), state.unsigned_senders(), transaction.clone())?;
+        let receipt = execute::<P>(state, transaction.rlp_hash(), valid, &header, &block_hashes);
         receipts.push(receipt);
+    }
+
+    let next_block = seal(state, current_block, block.transactions.clone(), receipts, &header);
+
+    if next_block.header.state_root != block.header.state_root {
+        Err(Error::StateRootMismatch)
+    } else if next_block.header.receipts_root != block.header.receipts_root {
+        Err(Error::ReceiptsRootMismatch)
+    } else if next_block.header.gas_used != block.header.gas_used {
+        Err(Error::GasUsedMismatch)
+    } else {
+        Ok(())
+    }
+}
+
+/// Read the next RLP item from a stream of concatenated items, or `None`
+/// at the end of the stream. Only lists are expected.
+fn read_rlp_list<R: Read>(reader: &mut R) -> Result<Option<Vec<u8>>, Error> {
+    let mut prefix = [0u8; 1];
+    if reader.read(&mut prefix)? == 0 {
+        return Ok(None);
+    }
+
+    let mut item = vec![prefix[0]];
+    let length = match prefix[0] {
+        0xc0..=0xf7 => (prefix[0] - 0xc0) as usize,
+        0xf8..=0xff => {
+            let mut length_bytes = vec![0u8; (prefix[0] - 0xf7) as usize];
+            reader.read_exact(&mut length_bytes)
+                .map_err(|_| Error::RlpError("truncated block".to_string()))?;
+            item.extend_from_slice(&length_bytes);
+            length_bytes.iter().fold(0usize, |length, byte| (length << 8) | (*byte as usize))
+        },
+        _ => return Err(Error::RlpError("expected a block".to_string())),
+    };
 
-        state.set_receipt_status(
-            transaction_hash,
-            match vm.status() {
-                VMStatus::ExitedOk => true,
-                _ => false,
+    let start = item.len();
+    item.resize(start + length, 0);
+    reader.read_exact(&mut item[start..])
+        .map_err(|_| Error::RlpError("truncated block".to_string()))?;
+    Ok(Some(item))
+}
+
+/// Import concatenated RLP blocks from a file, reading one block at a
+/// time. Blocks the chain already has are skipped. Returns the number of
+/// imported blocks.
+pub fn import_blocks<P: Patch>(state: &mut MinerState, path: &Path) -> Result<usize, Error> {
+    let mut reader = BufReader::new(File::open(path)?);
+
+    let mut imported = 0;
+    while let Some(item) = read_rlp_list(&mut reader)? {
+        let block: Block = UntrustedRlp::new(&item).as_val()?;
+
+        // Blocks already in the chain are skipped, as long as they are the
+        // same.
+        if block.header.number <= U256::from(state.block_height()) {
+            let number = block.header.number;
+            match state.get_block_by_number(number.as_usize()) {
+                Ok(ref local) if local.header.header_hash() == block.header.header_hash() => continue,
+                _ => return Err(Error::BlockHashMismatch(number)),
             }
-        );
+        }
 
-        println!("0x{:x}", transaction_hash);
+        let number = block.header.number;
+        import_block::<P>(state, block)?;
+        println!("imported block: {}", number);
+        imported += 1;
     }
 
-    let root = state.stateful_mut().root();
-    let next_block = next(&mut state, &current_block, transactions.as_ref(), receipts.as_ref(),
-                          beneficiary, Gas::from_str("0x10000000000000000000000").unwrap(),
-                          root);
-    debug!("block number: 0x{:x}", next_block.header.number);
-    state.append_block(next_block);
+    Ok(imported)
+}
+
+/// Write every block of the chain, from genesis to the current block, to
+/// a file as concatenated RLP.
+pub fn export_blocks(state: &MinerState, path: &Path) -> Result<usize, Error> {
+    let mut file = BufWriter::new(File::create(path)?);
+
+    for number in state.genesis_number()..(state.block_height() + 1) {
+        let block = state.get_block_by_number(number)?;
+        file.write_all(&rlp::encode(&block).to_vec())?;
+    }
+    file.flush()?;
+
+    Ok(state.block_height() + 1 - state.genesis_number())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn era_reward_reduction() {
+        let reward = BlockReward::Era { base: ether(5), era_length: 5000000 };
+
+        assert_eq!(reward.at(U256::from(1u64)), ether(5));
+        assert_eq!(reward.at(U256::from(5000000u64)), ether(5));
+        assert_eq!(reward.at(U256::from(5000001u64)), ether(4));
+        assert_eq!(reward.at(U256::from(10000001u64)), U256::from_dec_str("3200000000000000000").unwrap());
+    }
+
+    #[test]
+    fn read_concatenated_rlp_lists() {
+        let mut long = vec![0xf8, 0x38];
+        long.extend_from_slice(&[0x80; 0x38]);
+        let mut data = vec![0xc2, 0x01, 0x02];
+        data.extend_from_slice(&long);
+
+        let mut reader = &data[..];
+        assert_eq!(read_rlp_list(&mut reader).unwrap(), Some(vec![0xc2, 0x01, 0x02]));
+        assert_eq!(read_rlp_list(&mut reader).unwrap(), Some(long));
+        assert_eq!(read_rlp_list(&mut reader).unwrap(), None);
+
+        let mut truncated = &[0xc3, 0x01][..];
+        assert!(read_rlp_list(&mut truncated).is_err());
+    }
+
+    type P = ::sputnikvm_network_classic::MainnetEIP160Patch;
+
+    fn transfer(nonce: u64, to: Address) -> UnsignedTransaction {
+        UnsignedTransaction {
+            nonce: U256::from(nonce),
+            gas_price: Gas::zero(),
+            gas_limit: Gas::from(90000u64),
+            action: TransactionAction::Call(to),
+            value: U256::one(),
+            input: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn automine_one_block_per_transaction() {
+        let secret_key = SecretKey::from_slice(&SECP256K1, &[0x33; 32]).unwrap();
+        let sender = Address::from_secret_key(&secret_key).unwrap();
+        let to = Address::from(&[0xaa; 20][..]);
+        let mut state = make_state::<P>(vec![(secret_key.clone(), ether(1))], Gas::from(8000000u64), None, None, None);
+
+        let hash = state.append_pending_transaction(transfer(0, to).sign_global(&secret_key)).unwrap();
+        mine_transaction::<P>(&mut state, sender, hash);
+        assert_eq!(state.block_height(), 1);
+
+        // A nonce gap queues the transaction without mining.
+        let hash = state.append_pending_transaction(transfer(2, to).sign_global(&secret_key)).unwrap();
+        mine_transaction::<P>(&mut state, sender, hash);
+        assert_eq!(state.block_height(), 1);
+
+        // Filling the gap mines both, one block each.
+        let hash = state.append_pending_transaction(transfer(1, to).sign_global(&secret_key)).unwrap();
+        mine_transaction::<P>(&mut state, sender, hash);
+        assert_eq!(state.block_height(), 3);
+        assert_eq!(state.get_block_by_number(2).unwrap().transactions[0].nonce, U256::one());
+        assert_eq!(state.get_block_by_number(3).unwrap().transactions[0].nonce, U256::from(2u64));
+    }
+
+    #[test]
+    fn impersonated_transaction_stays_unsigned() {
+        let secret_key = SecretKey::from_slice(&SECP256K1, &[0x33; 32]).unwrap();
+        let impersonated = Address::from(&[0xaa; 20][..]);
+        let mut state = make_state::<P>(vec![(secret_key.clone(), ether(1))], Gas::from(8000000u64), None, None, None);
+
+        // Give the impersonated account some balance to send.
+        let hash = state.append_pending_transaction(UnsignedTransaction {
+            value: U256::from(1000u64), ..transfer(0, impersonated)
+        }.sign_global(&secret_key)).unwrap();
+        mine_transaction::<P>(&mut state, Address::from_secret_key(&secret_key).unwrap(), hash);
+
+        let transaction = unsigned_transaction(transfer(0, Address::default()));
+        assert!(transaction.caller().is_err());
+        assert!(state.append_unsigned_transaction(impersonated, transaction.clone()).is_err());
+
+        state.impersonate_account(impersonated);
+        let hash = state.append_unsigned_transaction(impersonated, transaction.clone()).unwrap();
+        mine_transaction::<P>(&mut state, impersonated, hash);
+        assert_eq!(state.block_height(), 2);
+        assert!(state.receipt_status(hash));
+
+        // The sender is still known once impersonation stops, but a
+        // transaction without a signature is never accepted as signed.
+        state.stop_impersonating_account(impersonated);
+        assert_eq!(state.transaction_sender(&transaction).unwrap(), impersonated);
+        assert_eq!(state.current_block().transactions, vec![transaction.clone()]);
+        assert!(to_valid::<P>(state.stateful(), &HashMap::new(), transaction).is_err());
+        assert!(state.append_pending_transaction(unsigned_transaction(transfer(1, Address::default()))).is_err());
+    }
+
+    #[test]
+    fn import_rejects_different_known_block() {
+        let secret_key = SecretKey::from_slice(&SECP256K1, &[0x33; 32]).unwrap();
+        let mut state = make_state::<P>(vec![(secret_key.clone(), ether(1))], Gas::from(8000000u64), None, None, None);
+        mine_one::<P>(&mut state);
+
+        let path = ::std::env::temp_dir().join(format!("svmdev-import-{}", ::std::process::id()));
+        assert_eq!(export_blocks(&state, &path).unwrap(), 2);
+        assert_eq!(import_blocks::<P>(&mut state, &path).unwrap(), 0);
+
+        let other_key = SecretKey::from_slice(&SECP256K1, &[0x44; 32]).unwrap();
+        let mut other = make_state::<P>(vec![(other_key, ether(1))], Gas::from(8000000u64), None, None, None);
+        match import_blocks::<P>(&mut other, &path) {
+            Err(Error::BlockHashMismatch(number)) => assert_eq!(number, U256::zero()),
+            result => panic!("expected a block hash mismatch, got {:?}", result),
+        }
+        let _ = ::std::fs::remove_file(&path);
+    }
+
+    #[test]
+    fn revert_restores_storage_and_clock() {
+        let secret_key = SecretKey::from_slice(&SECP256K1, &[0x33; 32]).unwrap();
+        let address = Address::from(&[0xaa; 20][..]);
+        let mut state = make_state::<P>(vec![(secret_key, ether(1))], Gas::from(8000000u64), None, None, None);
+
+        state.set_storage_at(address, U256::one(), M256::from(1u64)).unwrap();
+        let now = current_timestamp();
+        let id = state.snapshot();
+        state.set_storage_at(address, U256::one(), M256::from(2u64)).unwrap();
+        state.set_storage_at(Address::default(), U256::one(), M256::from(3u64)).unwrap();
+        state.set_next_timestamp(1000);
+        mine_one::<P>(&mut state);
+        state.set_next_timestamp(2000);
+
+        assert!(state.revert(id));
+        assert_eq!(state.block_height(), 0);
+        assert_eq!(state.account_storage(0, address).unwrap().get(&U256::one()), Some(&M256::from(1u64)));
+        assert!(state.account_storage(0, Address::default()).unwrap().is_empty());
+        // Neither the pending timestamp nor the clock moved back by mining
+        // at 1000 survive.
+        assert!(state.next_block_timestamp() >= now);
+    }
 }
@@ -0,0 +1,277 @@
+use bigint::{Address, H256, U256};
+use block::{Transaction, RlpHash};
+use std::collections::{HashMap, BTreeMap};
+
+use error::Error;
+
+struct PoolTransaction {
+    sequence: u64,
+    transaction: Transaction,
+}
+
+/// Transactions waiting to be mined, kept per sender and ordered by
+/// nonce. A transaction is pending when all nonces between the sender's
+/// current account nonce and its own are present, and queued otherwise.
+pub struct TransactionPool {
+    senders: HashMap<Address, BTreeMap<U256, PoolTransaction>>,
+    next_sequence: u64,
+}
+
+impl TransactionPool {
+    pub fn new() -> Self {
+        TransactionPool {
+            senders: HashMap::new(),
+            next_sequence: 0,
+        }
+    }
+
+    /// Add a transaction to the pool. A transaction with the same sender
+    /// and nonce as an existing one replaces it only if its gas price is
+    /// higher. Returns the hash of the replaced transaction. Inserting a
+    /// transaction already in the pool changes nothing.
+    pub fn insert(&mut self, sender: Address, transaction: Transaction) -> Result<Option<H256>, Error> {
+        let transactions = self.senders.entry(sender).or_insert(BTreeMap::new());
+
+        if let Some(existing) = transactions.get(&transaction.nonce) {
+            if existing.transaction == transaction {
+                return Ok(None);
+            }
+            if existing.transaction.gas_price >= transaction.gas_price {
+                return Err(Error::ReplacementUnderpriced);
+            }
+        }
+
+        let sequence = self.next_sequence;
+        self.next_sequence += 1;
+
+        let replaced = transactions.insert(transaction.nonce, PoolTransaction {
+            sequence, transaction
+        });
+        Ok(replaced.map(|replaced| replaced.transaction.rlp_hash()))
+    }
+
+    /// Whether the exact transaction is already in the pool.
+    pub fn contains(&self, sender: Address, transaction: &Transaction) -> bool {
+        match self.senders.get(&sender).and_then(|transactions| transactions.get(&transaction.nonce)) {
+            Some(existing) => &existing.transaction == transaction,
+            None => false,
+        }
+    }
+
+    /// The nonce the next transaction of the sender should use, given its
+    /// current account nonce.
+    pub fn pending_nonce(&self, sender: Address, nonce: U256) -> U256 {
+        let mut nonce = nonce;
+        if let Some(transactions) = self.senders.get(&sender) {
+            while transactions.contains_key(&nonce) {
+                nonce = nonce + U256::one();
+            }
+        }
+        nonce
+    }
+
+    /// All pending transactions in mining order, with their senders. Each sender's
+    /// transactions are in nonce order, and between senders the one with
+    /// the highest gas price goes first, with ties going to the earliest
+    /// submitted transaction.
+    pub fn pending<F: Fn(Address) -> U256>(&self, account_nonce: F) -> Vec<(Address, Transaction)> {
+        let mut next_nonces: HashMap<Address, U256> = self.senders.keys().map(|sender| {
+            (*sender, account_nonce(*sender))
+        }).collect();
+        let mut ret = Vec::new();
+
+        loop {
+            let mut best: Option<(Address, &PoolTransaction)> = None;
+
+            for (sender, transactions) in &self.senders {
+                let candidate = match transactions.get(&next_nonces[sender]) {
+                    Some(candidate) => candidate,
+                    None => continue,
+                };
+
+                let better = match best {
+                    None => true,
+                    Some((_, current)) => {
+                        candidate.transaction.gas_price > current.transaction.gas_price ||
+                            (candidate.transaction.gas_price == current.transaction.gas_price &&
+                             candidate.sequence < current.sequence)
+                    },
+                };
+                if better {
+                    best = Some((*sender, candidate));
+                }
+            }
+
+            match best {
+                Some((sender, pool_transaction)) => {
+                    ret.push((sender, pool_transaction.transaction.clone()));
+                    let next_nonce = next_nonces[&sender] + U256::one();
+                    next_nonces.insert(sender, next_nonce);
+                },
+                None => break,
+            }
+        }
+
+        ret
+    }
+
+    /// Every transaction in the pool, pending or queued, with its sender.
+    pub fn transactions(&self) -> Vec<(Address, Transaction)> {
+        let mut ret: Vec<(u64, Address, Transaction)> = Vec::new();
+        for (sender, transactions) in &self.senders {
+            for pool_transaction in transactions.values() {
+                ret.push((pool_transaction.sequence, *sender, pool_transaction.transaction.clone()));
+            }
+        }
+        ret.sort_by_key(|&(sequence, _, _)| sequence);
+        ret.into_iter().map(|(_, sender, transaction)| (sender, transaction)).collect()
+    }
+
+    /// Remove transactions whose nonce is below the sender's current
+    /// account nonce, returning their hashes.
+    pub fn prune<F: Fn(Address) -> U256>(&mut self, account_nonce: F) -> Vec<H256> {
+        let mut removed = Vec::new();
+
+        for (sender, transactions) in self.senders.iter_mut() {
+            let nonce = account_nonce(*sender);
+            let stale: Vec<U256> = transactions.keys().filter(|n| **n < nonce).cloned().collect();
+            for n in stale {
+                let pool_transaction = transactions.remove(&n).unwrap();
+                removed.push(pool_transaction.transaction.rlp_hash());
+            }
+        }
+        self.senders.retain(|_, transactions| !transactions.is_empty());
+
+        removed
+    }
+
+    /// Remove a single transaction, returning whether it was in the pool.
+    pub fn remove(&mut self, sender: Address, nonce: U256) -> bool {
+        let removed = match self.senders.get_mut(&sender) {
+            Some(transactions) => transactions.remove(&nonce).is_some(),
+            None => false,
+        };
+        self.senders.retain(|_, transactions| !transactions.is_empty());
+        removed
+    }
+
+    /// Remove every transaction, returning their hashes.
+    pub fn clear(&mut self) -> Vec<H256> {
+        let hashes = self.transactions().iter().map(|&(_, ref transaction)| {
+            transaction.rlp_hash()
+        }).collect();
+        self.senders.clear();
+        hashes
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bigint::Gas;
+    use block::{TransactionAction, TransactionSignature};
+
+    fn transaction(nonce: u64, gas_price: u64) -> Transaction {
+        Transaction {
+            nonce: U256::from(nonce),
+            gas_price: Gas::from(gas_price),
+            gas_limit: Gas::from(21000u64),
+            action: TransactionAction::Call(Address::default()),
+            value: U256::zero(),
+            signature: TransactionSignature {
+                v: 27,
+                r: H256::default(),
+                s: H256::default(),
+            },
+            input: Vec::new(),
+        }
+    }
+
+    fn nonces(pending: &[(Address, Transaction)]) -> Vec<(Address, u64)> {
+        pending.iter().map(|&(sender, ref transaction)| (sender, transaction.nonce.as_u64())).collect()
+    }
+
+    #[test]
+    fn pending_order() {
+        let alice = Address::from(&[1; 20][..]);
+        let bob = Address::from(&[2; 20][..]);
+        let carol = Address::from(&[3; 20][..]);
+
+        let mut pool = TransactionPool::new();
+        pool.insert(alice, transaction(0, 1)).unwrap();
+        pool.insert(alice, transaction(1, 5)).unwrap();
+        pool.insert(bob, transaction(0, 3)).unwrap();
+        pool.insert(carol, transaction(0, 3)).unwrap();
+
+        // Senders are ordered by gas price, with ties to the earliest
+        // submitted, and each sender's transactions stay in nonce order.
+        assert_eq!(nonces(&pool.pending(|_| U256::zero())),
+                   vec![(bob, 0), (carol, 0), (alice, 0), (alice, 1)]);
+    }
+
+    #[test]
+    fn replacement_needs_higher_gas_price() {
+        let sender = Address::from(&[1; 20][..]);
+
+        let mut pool = TransactionPool::new();
+        pool.insert(sender, transaction(0, 10)).unwrap();
+
+        let mut same_price = transaction(0, 10);
+        same_price.value = U256::one();
+        match pool.insert(sender, same_price) {
+            Err(Error::ReplacementUnderpriced) => (),
+            result => panic!("unexpected result: {:?}", result),
+        }
+        match pool.insert(sender, transaction(0, 9)) {
+            Err(Error::ReplacementUnderpriced) => (),
+            result => panic!("unexpected result: {:?}", result),
+        }
+
+        let replaced = pool.insert(sender, transaction(0, 11)).unwrap();
+        assert_eq!(replaced, Some(transaction(0, 10).rlp_hash()));
+        assert_eq!(pool.transactions().len(), 1);
+        assert!(pool.contains(sender, &transaction(0, 11)));
+        assert!(!pool.contains(sender, &transaction(0, 10)));
+    }
+
+    #[test]
+    fn resubmit_identical_transaction() {
+        let sender = Address::from(&[1; 20][..]);
+
+        let mut pool = TransactionPool::new();
+        pool.insert(sender, transaction(0, 10)).unwrap();
+        assert!(pool.contains(sender, &transaction(0, 10)));
+
+        assert_eq!(pool.insert(sender, transaction(0, 10)).unwrap(), None);
+        assert_eq!(pool.transactions().len(), 1);
+    }
+
+    #[test]
+    fn queued_transaction_is_promoted() {
+        let sender = Address::from(&[1; 20][..]);
+
+        let mut pool = TransactionPool::new();
+        pool.insert(sender, transaction(2, 1)).unwrap();
+        assert!(pool.pending(|_| U256::zero()).is_empty());
+        assert_eq!(pool.pending_nonce(sender, U256::zero()), U256::zero());
+
+        pool.insert(sender, transaction(0, 1)).unwrap();
+        assert_eq!(nonces(&pool.pending(|_| U256::zero())), vec![(sender, 0)]);
+
+        pool.insert(sender, transaction(1, 1)).unwrap();
+        assert_eq!(nonces(&pool.pending(|_| U256::zero())), vec![(sender, 0), (sender, 1), (sender, 2)]);
+        assert_eq!(pool.pending_nonce(sender, U256::zero()), U256::from(3u64));
+    }
+
+    #[test]
+    fn prune_mined_transactions() {
+        let sender = Address::from(&[1; 20][..]);
+
+        let mut pool = TransactionPool::new();
+        pool.insert(sender, transaction(0, 1)).unwrap();
+        pool.insert(sender, transaction(1, 1)).unwrap();
+
+        assert_eq!(pool.prune(|_| U256::one()), vec![transaction(0, 1).rlp_hash()]);
+        assert_eq!(nonces(&pool.pending(|_| U256::one())), vec![(sender, 1)]);
+    }
+}
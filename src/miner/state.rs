@@ -1,21 +1,95 @@
 use rlp;
 
 use error::Error;
-use block::{Receipt, Block, TotalHeader, UnsignedTransaction, Transaction, TransactionAction, Log, FromKey, Header, Account};
-use trie::{MemoryDatabase, MemoryDatabaseGuard, Trie};
+use block::{Receipt, Block, TotalHeader, Transaction, Account, RlpHash};
 use bigint::{H256, M256, U256, H64, B256, Gas, Address};
 use sha3::{Digest, Keccak256};
 use blockchain::chain::HeaderHash;
 use secp256k1::key::SecretKey;
-use sputnikvm::AccountChange;
-use sputnikvm_stateful::{MemoryStateful};
+use sputnikvm::{AccountChange, Storage};
 
-use std::sync::{Mutex, MutexGuard};
+use std::sync::Arc;
 use std::collections::{HashMap, HashSet};
+use std::path::PathBuf;
+use std::rc::Rc;
+use std::cmp;
+
+use super::{MiningMode, BlockReward, current_timestamp, empty_storage};
+use super::keystore::Keystore;
+use super::pool::TransactionPool;
+use super::database::{ChainDatabase, ChainStateful};
+use super::datadir::{DataDir, Record};
+use super::fork::{Fork, AccountInfo, LocalChanges};
+
+fn account_nonce(stateful: &ChainStateful, fork: &Option<Arc<Fork>>, number: usize, address: Address) -> U256 {
+    let account: Option<Account> = stateful.state_of(stateful.root()).get(&address);
+    match (account, fork) {
+        (Some(account), _) => account.nonce,
+        (None, &Some(ref fork)) if !fork.is_deleted(address, number) => match fork.account(address) {
+            Ok(Some(account)) => account.nonce,
+            _ => U256::zero(),
+        },
+        (None, _) => U256::zero(),
+    }
+}
+
+/// Storage value of an account at the given state root, which is the
+/// state of block `number`.
+pub fn storage_at(
+    stateful: &ChainStateful, fork: Option<&Fork>, root: H256, number: usize, address: Address, index: U256
+) -> Result<M256, Error> {
+    let account: Option<Account> = stateful.state_of(root).get(&address);
+    let local = match account {
+        Some(account) => stateful.storage_state_of(account.storage_root).get(&H256::from(index)),
+        None => None,
+    };
+
+    match (local, fork) {
+        (Some(value), _) => Ok(value),
+        (None, Some(fork)) => {
+            if fork.is_deleted(address, number) || fork.is_dirty(address, index, number) {
+                Ok(M256::zero())
+            } else {
+                fork.storage(address, index)
+            }
+        },
+        (None, None) => Ok(M256::zero()),
+    }
+}
+
+/// Write changed slots into the storage of an account. A zero value
+/// removes the slot.
+fn apply_storage_changes(storage: &mut HashMap<U256, M256>, changes: &HashMap<U256, M256>) {
+    for (key, value) in changes {
+        if *value == M256::zero() {
+            storage.remove(key);
+        } else {
+            storage.insert(*key, *value);
+        }
+    }
+}
+
+struct Snapshot {
+    block_height: usize,
+    /// Current state, which may differ from the state of the block after
+    /// `svm_set*` changes.
+    root: H256,
+    /// Storage of the accounts changed in `block_height` since the
+    /// snapshot was taken, as it was before, or `None` for accounts that
+    /// had no storage. Only changed accounts are kept, so taking a
+    /// snapshot does not copy the whole storage.
+    changed_storage: HashMap<Address, Option<HashMap<U256, M256>>>,
+    pending_transactions: Vec<(Address, Transaction)>,
+    pending_transaction_hashes: usize,
+    failures: HashMap<H256, String>,
+    time_offset: i64,
+    next_timestamp: Option<u64>,
+    fork_changes: Option<LocalChanges>,
+}
 
 pub struct MinerState {
     all_pending_transaction_hashes: Vec<H256>,
-    pending_transaction_hashes: Vec<H256>,
+    transaction_pool: TransactionPool,
     current_block: H256,
     block_hashes: Vec<H256>,
     transaction_block_hashes: HashMap<H256, H256>,
@@ -26,14 +100,35 @@ pub struct MinerState {
     receipt_database: HashMap<H256, Receipt>,
     fat_database: Vec<HashMap<Address, HashMap<U256, M256>>>,
     status_database: HashMap<H256, bool>,
-
-    accounts: Vec<SecretKey>,
-    database: &'static MemoryDatabase,
-    stateful: MemoryStateful<'static>,
+    revert_database: HashMap<H256, Vec<u8>>,
+    failure_database: HashMap<H256, String>,
+
+    accounts: Vec<Address>,
+    keystore: Keystore,
+    unlocked: HashMap<Address, (SecretKey, Option<u64>)>,
+    impersonated: HashSet<Address>,
+    /// Senders of the unsigned transactions sent from impersonated
+    /// accounts, by transaction hash.
+    unsigned_senders: HashMap<H256, Address>,
+    mining_mode: MiningMode,
+    network_id: u64,
+    chain_id: u64,
+    eip155: bool,
+    coinbase: Address,
+    block_reward: BlockReward,
+    block_gas_limit: Gas,
+    next_timestamp: Option<u64>,
+    time_offset: i64,
+    snapshots: Vec<Snapshot>,
+    database: &'static ChainDatabase,
+    stateful: ChainStateful,
+    datadir: Option<DataDir>,
+    fork: Option<Arc<Fork>>,
+    genesis_number: usize,
 }
 
 impl MinerState {
-    pub fn new(genesis: Block, stateful: MemoryStateful<'static>) -> Self {
+    pub fn new(genesis: Block, stateful: ChainStateful, datadir: Option<DataDir>) -> Self {
         let mut block_database = HashMap::new();
         let mut transaction_block_hashes = HashMap::new();
         let mut total_header_database = HashMap::new();
@@ -50,46 +145,215 @@ impl MinerState {
 
         let current_block = hash;
 
-        Self {
-            database: stateful.database(),
+        let mut state = Self {
+            database: stateful.database(), datadir,
+            fork: None,
+            genesis_number: genesis.header.number.as_usize(),
 
             block_database, transaction_block_hashes, total_header_database,
             block_hashes, current_block, stateful,
 
             all_pending_transaction_hashes: Vec::new(),
-            pending_transaction_hashes: Vec::new(),
+            transaction_pool: TransactionPool::new(),
             transaction_database: HashMap::new(),
             receipt_database: HashMap::new(),
             fat_database: vec![HashMap::new()],
             status_database: HashMap::new(),
+            revert_database: HashMap::new(),
+            failure_database: HashMap::new(),
 
             accounts: Vec::new(),
+            keystore: Keystore::new(),
+            unlocked: HashMap::new(),
+            impersonated: HashSet::new(),
+            unsigned_senders: HashMap::new(),
+            mining_mode: MiningMode::Automine,
+            network_id: 1,
+            chain_id: 1,
+            eip155: false,
+            coinbase: Address::default(),
+            block_reward: BlockReward::Fixed(U256::zero()),
+            block_gas_limit: genesis.header.gas_limit,
+            next_timestamp: None,
+            time_offset: 0,
+            snapshots: Vec::new(),
+        };
+
+        state.persist(Record::Block(genesis));
+        state
+    }
+
+    /// Rebuild the chain from the records of a data directory. The first
+    /// record is the genesis block, and the state trie is expected to be
+    /// already loaded into the database. A forked chain needs the fork it
+    /// was created with, pinned at its genesis block.
+    pub fn restore(
+        database: &'static ChainDatabase, datadir: DataDir, records: Vec<Record>, fork: Option<Fork>
+    ) -> Result<Self, Error> {
+        let mut records = records.into_iter();
+        let genesis = match records.next() {
+            Some(Record::Block(genesis)) => genesis,
+            _ => return Err(Error::NotFound),
+        };
+
+        let mut state = Self::new(genesis.clone(), ChainStateful::new(database, genesis.header.state_root), None);
+        state.fork = fork.map(Arc::new);
+        let mut forked = false;
+        for record in records {
+            match record {
+                Record::Account(secret_key) => state.append_account(secret_key),
+                Record::Block(block) => {
+                    let root = block.header.state_root;
+                    let number = block.header.number.as_usize();
+                    state.append_block(block);
+                    state.fat_storage(number)?;
+                    state.stateful = ChainStateful::new(database, root);
+                },
+                Record::Receipt(hash, receipt) => state.insert_receipt(hash, receipt),
+                Record::Status(hash, is_okay) => state.set_receipt_status(hash, is_okay),
+                Record::Revert(hash, output) => state.set_revert_output(hash, output),
+                Record::Sender(hash, sender) => { state.unsigned_senders.insert(hash, sender); },
+                Record::Storage(number, address, storage) => {
+                    let fat_storage = state.fat_storage(number)?;
+                    match storage {
+                        Some(storage) => { fat_storage.insert(address, storage); },
+                        None => { fat_storage.remove(&address); },
+                    }
+                },
+                Record::StorageChanges(number, address, changes) => {
+                    let fat_storage = state.fat_storage(number)?;
+                    apply_storage_changes(fat_storage.entry(address).or_insert(HashMap::new()), &changes);
+                },
+                Record::Rewind(height) => state.rewind(height)?,
+                Record::State(root) => state.stateful = ChainStateful::new(database, root),
+                Record::Fork(block) => {
+                    match state.fork {
+                        Some(ref fork) if fork.block_number() == block && genesis.header.number == block => (),
+                        Some(ref fork) => return Err(Error::InvalidParams(format!(
+                            "the chain was forked from block {}, not {}", block, fork.block_number()))),
+                        None => return Err(Error::InvalidParams(format!(
+                            "the chain was forked from block {}, and needs a fork url", block))),
+                    }
+                    forked = true;
+                },
+                Record::Dirty(changes) => if let Some(ref fork) = state.fork {
+                    fork.merge_local_changes(changes);
+                },
+                Record::LocalChanges(changes) => if let Some(ref fork) = state.fork {
+                    fork.set_local_changes(changes);
+                },
+            }
         }
+        if state.fork.is_some() && !forked {
+            return Err(Error::InvalidParams("the chain was not forked".to_string()));
+        }
+
+        state.datadir = Some(datadir);
+        Ok(state)
     }
 
-    pub fn append_pending_transaction(&mut self, transaction: Transaction) -> H256 {
-        let value = rlp::encode(&transaction).to_vec();
-        let hash = H256::from(Keccak256::digest(&value).as_slice());
+    /// Write a record to the data directory, if any. A failed write is
+    /// logged rather than stopping the node, at the cost of losing the
+    /// record on restart.
+    fn persist(&mut self, record: Record) {
+        if let Some(ref mut datadir) = self.datadir {
+            if let Err(err) = datadir.write(&record) {
+                error!("Failed to write to the data directory: {:?}", err);
+            }
+        }
+    }
+
+    pub fn append_pending_transaction(&mut self, transaction: Transaction) -> Result<H256, Error> {
+        let sender = transaction.caller().map_err(|_| Error::ECDSAError)?;
+        self.insert_pending_transaction(sender, transaction)
+    }
+
+    /// Queue an unsigned transaction from an impersonated account, keeping
+    /// its sender so that it can be executed and replayed later.
+    pub fn append_unsigned_transaction(&mut self, sender: Address, transaction: Transaction) -> Result<H256, Error> {
+        if !self.impersonated.contains(&sender) {
+            return Err(Error::UnknownAccount(sender));
+        }
+
+        // Unsigned transactions with the same fields have the same hash,
+        // whoever sends them.
+        let hash = transaction.rlp_hash();
+        match self.unsigned_senders.get(&hash) {
+            Some(known) if *known != sender =>
+                return Err(Error::InvalidParams(format!(
+                    "the same unsigned transaction was already sent from 0x{:x}", known))),
+            Some(_) => (),
+            None => {
+                self.unsigned_senders.insert(hash, sender);
+                self.persist(Record::Sender(hash, sender));
+            },
+        }
 
+        self.insert_pending_transaction(sender, transaction)
+    }
+
+    fn insert_pending_transaction(&mut self, sender: Address, transaction: Transaction) -> Result<H256, Error> {
+        let hash = transaction.rlp_hash();
+
+        // Sending the same transaction again is not an error.
+        if self.transaction_pool.contains(sender, &transaction) {
+            return Ok(hash);
+        }
+
+        if let Some(replaced) = self.transaction_pool.insert(sender, transaction.clone())? {
+            self.transaction_database.remove(&replaced);
+        }
         self.transaction_database.insert(hash, transaction);
-        self.pending_transaction_hashes.push(hash);
         self.all_pending_transaction_hashes.push(hash);
 
-        hash
+        Ok(hash)
+    }
+
+    /// Executable transactions in the order they should be mined.
+    pub fn pending_transactions(&self) -> Vec<(Address, Transaction)> {
+        let stateful = &self.stateful;
+        let fork = &self.fork;
+        let number = self.block_height();
+        self.transaction_pool.pending(|address| account_nonce(stateful, fork, number, address))
     }
 
-    pub fn clear_pending_transactions(&mut self) -> Vec<Transaction> {
-        let transaction_hashes = {
-            let ret_hashes = self.pending_transaction_hashes.clone();
-            self.pending_transaction_hashes.clear();
-            ret_hashes
+    /// Drop transactions that have been mined or can no longer be mined
+    /// because their nonce is too low.
+    pub fn prune_pending_transactions(&mut self) {
+        let removed = {
+            let stateful = &self.stateful;
+            let fork = &self.fork;
+            let number = self.block_height();
+            self.transaction_pool.prune(|address| account_nonce(stateful, fork, number, address))
         };
 
-        let mut transactions = Vec::new();
-        for hash in transaction_hashes {
-            transactions.push(self.transaction_database.get(&hash).unwrap().clone());
+        for hash in removed {
+            if !self.transaction_block_hashes.contains_key(&hash) {
+                self.transaction_database.remove(&hash);
+            }
         }
-        transactions
+    }
+
+    /// Remove a transaction that failed validation while mining, keeping
+    /// the reason so that it can be looked up by hash.
+    pub fn drop_pending_transaction(&mut self, sender: Address, transaction: &Transaction, reason: String) {
+        let hash = transaction.rlp_hash();
+        warn!("Dropped transaction 0x{:x}: {}", hash, reason);
+
+        self.transaction_pool.remove(sender, transaction.nonce);
+        self.transaction_database.remove(&hash);
+        self.failure_database.insert(hash, reason);
+    }
+
+    pub fn transaction_failure(&self, hash: H256) -> Option<String> {
+        self.failure_database.get(&hash).cloned()
+    }
+
+    /// Nonce for the next transaction of the address, counting pending
+    /// transactions in the pool.
+    pub fn pending_nonce(&self, address: Address) -> U256 {
+        let nonce = account_nonce(&self.stateful, &self.fork, self.block_height(), address);
+        self.transaction_pool.pending_nonce(address, nonce)
     }
 
     pub fn all_pending_transaction_hashes(&self) -> Vec<H256> {
@@ -104,6 +368,7 @@ impl MinerState {
         for transaction in &block.transactions {
             let transaction_hash = H256::from(Keccak256::digest(&rlp::encode(transaction).to_vec()).as_slice());
             self.transaction_block_hashes.insert(transaction_hash, hash);
+            self.transaction_database.insert(transaction_hash, transaction.clone());
         }
 
         assert!(self.block_hashes.len() > 0);
@@ -114,68 +379,113 @@ impl MinerState {
         self.block_hashes.push(hash);
         self.current_block = hash;
 
+        self.persist(Record::Block(block));
         hash
     }
 
-    pub fn fat_transit(&mut self, number: usize, accounts: &[AccountChange]) {
-        while number >= self.fat_database.len() {
+    /// Index of a block in the per-block tables, or `None` if the number is
+    /// below the genesis block or above the current block.
+    fn block_index(&self, number: usize) -> Option<usize> {
+        if number < self.genesis_number || number > self.block_height() {
+            None
+        } else {
+            Some(number - self.genesis_number)
+        }
+    }
+
+    fn fat_storage(&mut self, number: usize) -> Result<&mut HashMap<Address, HashMap<U256, M256>>, Error> {
+        if number < self.genesis_number {
+            return Err(Error::NotFound);
+        }
+
+        let index = number - self.genesis_number;
+        while index >= self.fat_database.len() {
             let last = self.fat_database.last().unwrap().clone();
             self.fat_database.push(last);
         }
 
-        let database = &mut self.fat_database[number];
-        for account in accounts {
-            match account.clone() {
-                AccountChange::Full {
-                    address, changing_storage, ..
-                } => {
-                    let changing_storage: HashMap<U256, M256> = changing_storage.into();
+        Ok(&mut self.fat_database[index])
+    }
 
-                    let fat_storage = database.entry(address).or_insert(HashMap::new());
+    pub fn fat_transit(&mut self, number: usize, accounts: &[AccountChange]) -> Result<(), Error> {
+        let mut records = Vec::new();
+
+        // Snapshots taken at this block keep the storage of the accounts
+        // about to change, the first time each one changes.
+        if self.snapshots.iter().any(|snapshot| snapshot.block_height == number) {
+            for account in accounts {
+                let address = account.address();
+                let storage = self.fat_storage(number)?.get(&address).cloned();
+                for snapshot in self.snapshots.iter_mut().filter(|snapshot| snapshot.block_height == number) {
+                    snapshot.changed_storage.entry(address).or_insert_with(|| storage.clone());
+                }
+            }
+        }
 
-                    for (key, value) in changing_storage {
-                        if value == M256::zero() {
-                            fat_storage.remove(&key);
-                        } else {
-                            fat_storage.insert(key, value);
-                        }
-                    }
-                },
-                AccountChange::IncreaseBalance(address, _) => {
-                    database.entry(address).or_insert(HashMap::new());
-                },
-                AccountChange::Create {
-                    address, storage, ..
-                } => {
-                    let storage: HashMap<U256, M256> = storage.into();
-
-                    let fat_storage = database.entry(address).or_insert(HashMap::new());
-
-                    for (key, value) in storage {
-                        if value == M256::zero() {
-                            fat_storage.remove(&key);
-                        } else {
-                            fat_storage.insert(key, value);
-                        }
+        {
+            let database = self.fat_storage(number)?;
+            for account in accounts {
+                match account.clone() {
+                    AccountChange::Full {
+                        address, changing_storage, ..
+                    } => {
+                        let changing_storage: HashMap<U256, M256> = changing_storage.into();
+                        apply_storage_changes(database.entry(address).or_insert(HashMap::new()), &changing_storage);
+                        records.push(Record::StorageChanges(number, address, changing_storage));
+                    },
+                    AccountChange::IncreaseBalance(address, _) => {
+                        database.entry(address).or_insert(HashMap::new());
+                        records.push(Record::StorageChanges(number, address, HashMap::new()));
+                    },
+                    AccountChange::Create {
+                        address, storage, ..
+                    } => {
+                        let storage: HashMap<U256, M256> = storage.into();
+                        apply_storage_changes(database.entry(address).or_insert(HashMap::new()), &storage);
+                        records.push(Record::StorageChanges(number, address, storage));
+                    },
+                    AccountChange::Nonexist(address) => {
+                        database.remove(&address);
+                        records.push(Record::Storage(number, address, None));
                     }
-                },
-                AccountChange::Nonexist(address) => {
-                    database.remove(&address);
                 }
             }
         }
+
+        // Only the changed slots are written, so that the data directory
+        // grows with the changes rather than with the size of the storage.
+        for record in records {
+            self.persist(record);
+        }
+        Ok(())
+    }
+
+    pub fn dump_accounts(&self, number: usize) -> Result<HashMap<Address, HashMap<U256, M256>>, Error> {
+        let index = self.block_index(number).ok_or(Error::NotFound)?;
+        let index = cmp::min(index, self.fat_database.len() - 1);
+        Ok(self.fat_database[index].clone())
     }
 
-    pub fn dump_accounts(&self, number: usize) -> HashMap<Address, HashMap<U256, M256>> {
-        self.fat_database[number].clone()
+    /// Full storage of an account after the given block.
+    pub fn account_storage(&self, number: usize, address: Address) -> Result<HashMap<U256, M256>, Error> {
+        let index = self.block_index(number).ok_or(Error::NotFound)?;
+        let index = cmp::min(index, self.fat_database.len() - 1);
+        Ok(self.fat_database[index].get(&address).cloned().unwrap_or_default())
     }
 
     pub fn insert_receipt(&mut self, transaction_hash: H256, receipt: Receipt) {
-        self.receipt_database.insert(transaction_hash, receipt);
+        self.receipt_database.insert(transaction_hash, receipt.clone());
+        self.persist(Record::Receipt(transaction_hash, receipt));
     }
 
     pub fn block_height(&self) -> usize {
-        self.block_hashes.len() - 1
+        self.genesis_number + self.block_hashes.len() - 1
+    }
+
+    /// Number of the genesis block, which is the pinned block in fork
+    /// mode and zero otherwise.
+    pub fn genesis_number(&self) -> usize {
+        self.genesis_number
     }
 
     pub fn get_transaction_block_hash_by_hash(&self, key: H256) -> Result<H256, Error> {
@@ -194,20 +504,29 @@ impl MinerState {
         self.receipt_database.get(&key).map(|v| v.clone()).ok_or(Error::NotFound)
     }
 
-    pub fn get_block_by_number(&self, index: usize) -> Block {
-        self.get_block_by_hash(self.block_hashes[index]).unwrap()
+    pub fn get_block_by_number(&self, number: usize) -> Result<Block, Error> {
+        let index = self.block_index(number).ok_or(Error::NotFound)?;
+        self.get_block_by_hash(self.block_hashes[index])
     }
 
     pub fn get_total_header_by_hash(&self, key: H256) -> Result<TotalHeader, Error> {
         self.total_header_database.get(&key).map(|v| v.clone()).ok_or(Error::NotFound)
     }
 
-    pub fn get_total_header_by_number(&self, index: usize) -> TotalHeader {
-        self.total_header_database.get(&self.block_hashes[index]).map(|v| v.clone()).unwrap()
+    pub fn get_total_header_by_number(&self, number: usize) -> Result<TotalHeader, Error> {
+        let index = self.block_index(number).ok_or(Error::NotFound)?;
+        self.get_total_header_by_hash(self.block_hashes[index])
     }
 
-    pub fn get_last_256_block_hashes_by_number(&self, number: usize) -> Vec<H256> {
-        let mut hashes: Vec<H256> = (&self.block_hashes[0..number]).into();
+    /// Hashes of the up to 256 blocks before the given one, most recent
+    /// first. The number may be one above the current block, for the block
+    /// being mined.
+    pub fn get_last_256_block_hashes_by_number(&self, number: usize) -> Result<Vec<H256>, Error> {
+        if number < self.genesis_number || number > self.block_height() + 1 {
+            return Err(Error::NotFound);
+        }
+
+        let mut hashes: Vec<H256> = (&self.block_hashes[0..(number - self.genesis_number)]).into();
         let mut ret = Vec::new();
 
         for _ in 0..256 {
@@ -217,42 +536,456 @@ impl MinerState {
             }
         }
 
-        ret
+        Ok(ret)
     }
 
+    /// Hashes of the blocks before the block being mined.
     pub fn get_last_256_block_hashes(&self) -> Vec<H256> {
-        self.get_last_256_block_hashes_by_number(self.current_block().header.number.as_usize())
+        self.get_last_256_block_hashes_by_number(self.block_height() + 1).unwrap()
     }
 
     pub fn current_block(&self) -> Block {
-        self.get_block_by_number(self.block_height())
+        self.get_block_by_hash(self.current_block).unwrap()
     }
 
-    pub fn stateful_mut(&mut self) -> &mut MemoryStateful<'static> {
+    pub fn stateful_mut(&mut self) -> &mut ChainStateful {
         &mut self.stateful
     }
 
-    pub fn stateful(&self) -> &MemoryStateful<'static> {
+    pub fn stateful(&self) -> &ChainStateful {
         &self.stateful
     }
 
-    pub fn stateful_at(&self, root: H256) -> MemoryStateful<'static> {
-        MemoryStateful::new(self.database, root)
+    pub fn stateful_at(&self, root: H256) -> ChainStateful {
+        ChainStateful::new(self.database, root)
+    }
+
+    pub fn fork(&self) -> Option<Arc<Fork>> {
+        self.fork.clone()
     }
 
-    pub fn accounts(&self) -> Vec<SecretKey> {
+    pub fn set_fork(&mut self, fork: Fork) {
+        self.persist(Record::Fork(fork.block_number()));
+        self.fork = Some(Arc::new(fork));
+    }
+
+    /// Remember the accounts changed locally in block `number`, so that
+    /// they are no longer read from upstream in fork mode.
+    pub fn mark_dirty(&mut self, number: usize, accounts: &[AccountChange]) {
+        let marked = match self.fork {
+            Some(ref fork) => fork.mark_dirty(number, accounts),
+            None => return,
+        };
+        if !marked.is_empty() {
+            self.persist(Record::Dirty(marked));
+        }
+    }
+
+    /// Nonce, balance and code of an account at the given state root,
+    /// which is the state of block `number`. In fork mode, an account
+    /// missing locally is read from the upstream node, unless it was
+    /// deleted locally.
+    pub fn account_at(&self, root: H256, number: usize, address: Address) -> Result<Option<AccountInfo>, Error> {
+        let account: Option<Account> = self.stateful.state_of(root).get(&address);
+        match account {
+            Some(account) => Ok(Some(AccountInfo {
+                nonce: account.nonce,
+                balance: account.balance,
+                code: self.stateful.code(account.code_hash).ok_or(Error::NotFound)?,
+            })),
+            None => match self.fork {
+                Some(ref fork) if !fork.is_deleted(address, number) => fork.account(address),
+                _ => Ok(None),
+            },
+        }
+    }
+
+    /// Storage value of an account at the given state root. In fork mode,
+    /// a value never written locally is read from the upstream node.
+    pub fn storage_at(&self, root: H256, number: usize, address: Address, index: U256) -> Result<M256, Error> {
+        storage_at(&self.stateful, self.fork.as_ref().map(|fork| &**fork), root, number, address, index)
+    }
+
+    /// State root of a block. For the latest block this is the current
+    /// state, which also holds the changes made by `svm_set*` since the
+    /// block was mined.
+    pub fn state_root_at(&self, number: usize) -> Result<H256, Error> {
+        if number == self.block_height() {
+            Ok(self.stateful.root())
+        } else {
+            Ok(self.get_block_by_number(number)?.header.state_root)
+        }
+    }
+
+    /// Change an account of the current state directly. The change is
+    /// committed by the next mined block.
+    fn edit_account<F: FnOnce(&mut AccountInfo, &mut Storage)>(&mut self, address: Address, edit: F) -> Result<(), Error> {
+        let number = self.block_height();
+        if let Some(ref fork) = self.fork {
+            fork.materialize(&mut self.stateful, address, number)?;
+        }
+
+        let account: Option<Account> = self.stateful.state_of(self.stateful.root()).get(&address);
+        let mut info = match account {
+            Some(ref account) => AccountInfo {
+                nonce: account.nonce,
+                balance: account.balance,
+                code: self.stateful.code(account.code_hash).ok_or(Error::NotFound)?,
+            },
+            None => AccountInfo {
+                nonce: U256::zero(),
+                balance: U256::zero(),
+                code: Vec::new(),
+            },
+        };
+        let code = info.code.clone();
+        let mut storage = empty_storage(address);
+        edit(&mut info, &mut storage);
+
+        let change = if account.is_some() && info.code == code {
+            AccountChange::Full {
+                nonce: info.nonce,
+                address,
+                balance: info.balance,
+                changing_storage: storage,
+                code: Rc::new(info.code),
+            }
+        } else {
+            // Create replaces the whole storage, so the current one is
+            // carried over.
+            let mut full_storage = empty_storage(address);
+            if let Some(current) = self.fat_storage(number)?.get(&address) {
+                for (index, value) in current {
+                    full_storage.write(*index, *value).unwrap();
+                }
+            }
+            let storage: HashMap<U256, M256> = storage.into();
+            for (index, value) in storage {
+                full_storage.write(index, value).unwrap();
+            }
+
+            AccountChange::Create {
+                nonce: info.nonce,
+                address,
+                balance: info.balance,
+                storage: full_storage,
+                code: Rc::new(info.code),
+            }
+        };
+
+        self.mark_dirty(number, &[change.clone()]);
+        self.stateful.transit(&[change.clone()]);
+        let root = self.stateful.root();
+        self.persist(Record::State(root));
+        self.fat_transit(number, &[change])
+    }
+
+    pub fn set_balance(&mut self, address: Address, balance: U256) -> Result<(), Error> {
+        self.edit_account(address, |account, _| account.balance = balance)
+    }
+
+    pub fn set_nonce(&mut self, address: Address, nonce: U256) -> Result<(), Error> {
+        self.edit_account(address, |account, _| account.nonce = nonce)
+    }
+
+    pub fn set_code(&mut self, address: Address, code: Vec<u8>) -> Result<(), Error> {
+        self.edit_account(address, |account, _| account.code = code)
+    }
+
+    pub fn set_storage_at(&mut self, address: Address, index: U256, value: M256) -> Result<(), Error> {
+        self.edit_account(address, |_, storage| storage.write(index, value).unwrap())
+    }
+
+    pub fn accounts(&self) -> Vec<Address> {
         self.accounts.clone()
     }
 
+    /// Add an account generated at startup. It stays unlocked until it is
+    /// locked explicitly.
     pub fn append_account(&mut self, key: SecretKey) {
-        self.accounts.push(key)
+        let address = self.keystore.insert_generated(key.clone()).unwrap();
+        if !self.accounts.contains(&address) {
+            self.accounts.push(address);
+        }
+        self.unlocked.insert(address, (key.clone(), None));
+        self.persist(Record::Account(key));
+    }
+
+    /// Store key files in the directory, and add the accounts of the key
+    /// files already there, locked.
+    pub fn open_keystore(&mut self, dir: PathBuf) -> Result<(), Error> {
+        for address in self.keystore.open(dir)? {
+            if !self.accounts.contains(&address) {
+                self.accounts.push(address);
+            }
+        }
+        Ok(())
+    }
+
+    /// Add a locked account, stored encrypted with the password.
+    pub fn new_account(&mut self, key: SecretKey, password: &str) -> Result<Address, Error> {
+        let address = self.keystore.insert(&key, password)?;
+        if !self.accounts.contains(&address) {
+            self.accounts.push(address);
+        }
+        Ok(address)
+    }
+
+    /// Decrypt the key of an account, without unlocking it.
+    pub fn decrypt_account(&self, address: Address, password: &str) -> Result<SecretKey, Error> {
+        self.keystore.decrypt(address, password)
+    }
+
+    /// Unlock an account for the given number of seconds, or until it is
+    /// locked if no duration is given.
+    pub fn unlock_account(&mut self, address: Address, password: &str, duration: Option<u64>) -> Result<(), Error> {
+        let key = self.keystore.decrypt(address, password)?;
+        let until = duration.map(|duration| current_timestamp() + duration);
+        self.unlocked.insert(address, (key, until));
+        Ok(())
+    }
+
+    pub fn lock_account(&mut self, address: Address) -> bool {
+        self.unlocked.remove(&address).is_some()
+    }
+
+    /// Key of an unlocked account.
+    pub fn secret_key(&self, address: Address) -> Result<SecretKey, Error> {
+        match self.unlocked.get(&address) {
+            Some(&(ref key, None)) => Ok(key.clone()),
+            Some(&(ref key, Some(until))) if current_timestamp() < until => Ok(key.clone()),
+            _ if self.keystore.contains(address) => Err(Error::AccountLocked),
+            _ => Err(Error::UnknownAccount(address)),
+        }
+    }
+
+    pub fn mining_mode(&self) -> MiningMode {
+        self.mining_mode
+    }
+
+    pub fn set_mining_mode(&mut self, mode: MiningMode) {
+        self.mining_mode = mode;
+    }
+
+    pub fn impersonate_account(&mut self, address: Address) {
+        self.impersonated.insert(address);
+    }
+
+    pub fn stop_impersonating_account(&mut self, address: Address) -> bool {
+        self.impersonated.remove(&address)
+    }
+
+    pub fn is_impersonated(&self, address: Address) -> bool {
+        self.impersonated.contains(&address)
+    }
+
+    pub fn unsigned_senders(&self) -> &HashMap<H256, Address> {
+        &self.unsigned_senders
+    }
+
+    /// Sender of a transaction in the pool or the chain.
+    pub fn transaction_sender(&self, transaction: &Transaction) -> Result<Address, Error> {
+        super::transaction_sender(&self.unsigned_senders, transaction)
+    }
+
+    pub fn network_id(&self) -> u64 {
+        self.network_id
+    }
+
+    pub fn chain_id(&self) -> u64 {
+        self.chain_id
+    }
+
+    /// Chain id that transactions are signed with, or `None` if the patch
+    /// predates EIP-155.
+    pub fn signing_chain_id(&self) -> Option<u64> {
+        if self.eip155 {
+            Some(self.chain_id)
+        } else {
+            None
+        }
+    }
+
+    pub fn set_chain_ids(&mut self, network_id: u64, chain_id: u64, eip155: bool) {
+        self.network_id = network_id;
+        self.chain_id = chain_id;
+        self.eip155 = eip155;
+    }
+
+    pub fn coinbase(&self) -> Address {
+        self.coinbase
+    }
+
+    pub fn set_coinbase(&mut self, coinbase: Address) {
+        self.coinbase = coinbase;
+    }
+
+    pub fn block_reward(&self) -> BlockReward {
+        self.block_reward
+    }
+
+    pub fn set_block_reward(&mut self, block_reward: BlockReward) {
+        self.block_reward = block_reward;
+    }
+
+    pub fn block_gas_limit(&self) -> Gas {
+        self.block_gas_limit
+    }
+
+    /// Current time of the chain clock, which is the wall clock plus any
+    /// offset added by time travel.
+    pub fn timestamp(&self) -> u64 {
+        (current_timestamp() as i64 + self.time_offset) as u64
+    }
+
+    /// Move the chain clock forward by the given number of seconds,
+    /// returning the total offset from the wall clock.
+    pub fn increase_time(&mut self, seconds: u64) -> i64 {
+        self.time_offset += seconds as i64;
+        self.time_offset
+    }
+
+    pub fn set_next_timestamp(&mut self, timestamp: u64) {
+        self.next_timestamp = Some(timestamp);
+    }
+
+    /// Timestamp for the block about to be mined. A timestamp set with
+    /// `set_next_timestamp` is used once, and the chain clock continues
+    /// from it afterwards.
+    pub fn next_block_timestamp(&mut self) -> u64 {
+        match self.next_timestamp.take() {
+            Some(timestamp) => {
+                self.time_offset = timestamp as i64 - current_timestamp() as i64;
+                timestamp
+            },
+            None => self.timestamp(),
+        }
+    }
+
+    /// Save the current block height, state, pending transactions and
+    /// chain clock, returning an id that can later be passed to `revert`.
+    /// Ids start from 1. Storage is not copied here, but by `fat_transit`
+    /// as accounts change.
+    pub fn snapshot(&mut self) -> usize {
+        let block_height = self.block_height();
+        self.snapshots.push(Snapshot {
+            block_height,
+            root: self.stateful.root(),
+            changed_storage: HashMap::new(),
+            pending_transactions: self.transaction_pool.transactions(),
+            pending_transaction_hashes: self.all_pending_transaction_hashes.len(),
+            failures: self.failure_database.clone(),
+            time_offset: self.time_offset,
+            next_timestamp: self.next_timestamp,
+            fork_changes: self.fork.as_ref().map(|fork| fork.local_changes()),
+        });
+        self.snapshots.len()
+    }
+
+    /// Roll back to the given snapshot. The snapshot itself and all
+    /// snapshots taken after it are discarded. Returns false if the id is
+    /// unknown.
+    pub fn revert(&mut self, id: usize) -> bool {
+        if id == 0 || id > self.snapshots.len() {
+            return false;
+        }
+
+        let snapshot = self.snapshots.drain((id - 1)..).next().unwrap();
+        if self.rewind(snapshot.block_height).is_err() {
+            return false;
+        }
+        self.stateful = ChainStateful::new(self.database, snapshot.root);
+        self.persist(Record::State(snapshot.root));
+
+        // Blocks mined since are gone with `rewind`, which leaves the
+        // changes made to the snapshot's block itself.
+        let number = snapshot.block_height;
+        for (address, storage) in snapshot.changed_storage {
+            {
+                let fat_storage = self.fat_storage(number).unwrap();
+                match storage {
+                    Some(ref storage) => { fat_storage.insert(address, storage.clone()); },
+                    None => { fat_storage.remove(&address); },
+                }
+            }
+            self.persist(Record::Storage(number, address, storage));
+        }
+        self.time_offset = snapshot.time_offset;
+        self.next_timestamp = snapshot.next_timestamp;
+        self.failure_database = snapshot.failures;
+        self.all_pending_transaction_hashes.truncate(snapshot.pending_transaction_hashes);
+        if let (Some(fork), Some(changes)) = (self.fork.clone(), snapshot.fork_changes) {
+            fork.set_local_changes(changes.clone());
+            self.persist(Record::LocalChanges(changes));
+        }
+
+        for hash in self.transaction_pool.clear() {
+            self.transaction_database.remove(&hash);
+        }
+        for (sender, transaction) in snapshot.pending_transactions {
+            let hash = transaction.rlp_hash();
+            self.transaction_pool.insert(sender, transaction.clone()).unwrap();
+            self.transaction_database.insert(hash, transaction);
+        }
+
+        true
+    }
+
+    /// Drop all blocks above the given height, and reset the state to
+    /// that block.
+    pub fn rewind(&mut self, height: usize) -> Result<(), Error> {
+        let index = self.block_index(height).ok_or(Error::NotFound)?;
+        while self.block_hashes.len() > index + 1 {
+            let hash = self.block_hashes.pop().unwrap();
+            let block = self.block_database.remove(&hash).unwrap();
+            self.total_header_database.remove(&hash);
+
+            for transaction in &block.transactions {
+                let transaction_hash = transaction.rlp_hash();
+                self.transaction_block_hashes.remove(&transaction_hash);
+                self.transaction_database.remove(&transaction_hash);
+                self.receipt_database.remove(&transaction_hash);
+                self.status_database.remove(&transaction_hash);
+                self.revert_database.remove(&transaction_hash);
+            }
+        }
+        self.fat_database.truncate(index + 1);
+        if let Some(ref fork) = self.fork {
+            fork.rewind(height);
+        }
+
+        self.current_block = self.block_hashes[index];
+        let root = self.get_block_by_hash(self.current_block).unwrap().header.state_root;
+        self.stateful = ChainStateful::new(self.database, root);
+
+        self.persist(Record::Rewind(height));
+        Ok(())
+    }
+
+    /// Forget the receipt, status and revert output recorded for a
+    /// transaction of a block that was not appended.
+    pub fn discard_transaction_results(&mut self, transaction_hash: H256) {
+        self.receipt_database.remove(&transaction_hash);
+        self.status_database.remove(&transaction_hash);
+        self.revert_database.remove(&transaction_hash);
     }
 
     pub fn set_receipt_status(&mut self, transaction_hash: H256, is_okay: bool) {
         self.status_database.insert(transaction_hash, is_okay);
+        self.persist(Record::Status(transaction_hash, is_okay));
     }
 
     pub fn receipt_status(&self, transaction_hash: H256) -> bool {
         *self.status_database.get(&transaction_hash).unwrap_or(&false)
     }
+
+    pub fn set_revert_output(&mut self, transaction_hash: H256, output: Vec<u8>) {
+        self.revert_database.insert(transaction_hash, output.clone());
+        self.persist(Record::Revert(transaction_hash, output));
+    }
+
+    /// Output of a mined transaction that failed, if any.
+    pub fn revert_output(&self, transaction_hash: H256) -> Option<&[u8]> {
+        self.revert_database.get(&transaction_hash).map(|output| output.as_slice())
+    }
 }
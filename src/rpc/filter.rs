@@ -64,7 +64,7 @@ pub fn get_logs(state: &MinerState, filter: LogFilter) -> Result<Vec<RPCLog>, Er
             break;
         }
 
-        let block = state.get_block_by_number(current_block_number);
+        let block = state.get_block_by_number(current_block_number)?;
         for transaction in &block.transactions {
             let transaction_hash = H256::from(Keccak256::digest(&rlp::encode(transaction).to_vec()).as_slice());
             let receipt = state.get_receipt_by_transaction_hash(transaction_hash)?;
@@ -163,6 +163,10 @@ impl FilterManager {
         match filter {
             &mut Filter::PendingTransaction(ref mut next_start) => {
                 let pending_transactions = state.all_pending_transaction_hashes();
+                // `evm_revert` may have dropped hashes this filter has seen.
+                if *next_start > pending_transactions.len() {
+                    *next_start = pending_transactions.len();
+                }
                 let mut ret = Vec::new();
                 while *next_start < pending_transactions.len() {
                     ret.push(format!("0x{:x}", &pending_transactions[*next_start]));
@@ -174,7 +178,7 @@ impl FilterManager {
                 let mut ret = Vec::new();
                 while *next_start <= state.block_height() {
                     ret.push(format!("0x{:x}",
-                                     state.get_block_by_number(*next_start).header.header_hash()));
+                                     state.get_block_by_number(*next_start)?.header.header_hash()));
                     *next_start += 1;
                 }
                 Ok(Either::Left(ret))
@@ -10,6 +10,8 @@ use std::net::SocketAddr;
 use std::sync::{Arc, Mutex};
 use std::sync::mpsc::{channel, Sender, Receiver};
 use std::collections::HashMap;
+use std::fs::File;
+use std::path::Path;
 use sputnikvm::Patch;
 
 mod serves;
@@ -17,9 +19,11 @@ mod filter;
 mod util;
 mod serialize;
 mod solidity;
+mod typed_data;
+mod proof;
 
 use error::Error;
-use super::miner::MinerState;
+use super::miner::{MinerState, Genesis};
 use self::serialize::*;
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
@@ -71,6 +75,8 @@ pub struct RPCReceipt {
     pub logs: Vec<RPCLog>,
     pub root: Hex<H256>,
     pub status: usize,
+    /// Decoded `Error(string)` message of a failed transaction.
+    pub revert_reason: Option<String>,
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
@@ -114,6 +120,27 @@ pub struct RPCTransaction {
     pub transaction_index: Option<Hex<usize>>,
 }
 
+/// Changes made to an account before running `eth_call`. `state` replaces
+/// the whole storage, while `stateDiff` only changes the given slots.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct RPCAccountOverride {
+    pub balance: Option<Hex<U256>>,
+    pub nonce: Option<Hex<U256>>,
+    pub code: Option<Bytes>,
+    pub state: Option<HashMap<Hex<U256>, Hex<U256>>>,
+    pub state_diff: Option<HashMap<Hex<U256>, Hex<U256>>>,
+}
+
+/// Header fields changed before running `eth_call`.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct RPCBlockOverrides {
+    pub number: Option<Hex<U256>>,
+    pub time: Option<Hex<U256>>,
+    pub coinbase: Option<Hex<Address>>,
+}
+
 #[derive(Serialize, Deserialize, Debug, Clone)]
 #[serde(rename_all = "camelCase")]
 pub struct RPCTrace {
@@ -192,6 +219,28 @@ pub struct RPCDumpAccount {
     pub storage: HashMap<Hex<U256>, Hex<M256>>,
 }
 
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct RPCStorageProof {
+    pub key: Hex<U256>,
+    pub value: Hex<M256>,
+    pub proof: Vec<Bytes>,
+}
+
+/// An account and some of its storage, with the Merkle proofs of both,
+/// as returned by `eth_getProof` (EIP-1186).
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct RPCProof {
+    pub address: Hex<Address>,
+    pub account_proof: Vec<Bytes>,
+    pub balance: Hex<U256>,
+    pub code_hash: Hex<H256>,
+    pub nonce: Hex<U256>,
+    pub storage_hash: Hex<H256>,
+    pub storage_proof: Vec<RPCStorageProof>,
+}
+
 build_rpc_trait! {
     pub trait EthereumRPC {
         #[rpc(name = "web3_clientVersion")]
@@ -210,6 +259,8 @@ build_rpc_trait! {
         fn protocol_version(&self) -> Result<String, Error>;
         #[rpc(name = "eth_syncing")]
         fn is_syncing(&self) -> Result<bool, Error>;
+        #[rpc(name = "eth_chainId")]
+        fn chain_id(&self) -> Result<Hex<u64>, Error>;
         #[rpc(name = "eth_coinbase")]
         fn coinbase(&self) -> Result<Hex<Address>, Error>;
         #[rpc(name = "eth_mining")]
@@ -238,15 +289,21 @@ build_rpc_trait! {
         fn block_uncles_count_by_number(&self, String) -> Result<Option<Hex<usize>>, Error>;
         #[rpc(name = "eth_getCode")]
         fn code(&self, Hex<Address>, Trailing<String>) -> Result<Bytes, Error>;
+        #[rpc(name = "eth_getProof")]
+        fn proof(&self, Hex<Address>, Vec<Hex<U256>>, Trailing<String>) -> Result<RPCProof, Error>;
         #[rpc(name = "eth_sign")]
         fn sign(&self, Hex<Address>, Bytes) -> Result<Bytes, Error>;
+        #[rpc(name = "eth_signTransaction")]
+        fn sign_transaction(&self, RPCTransaction) -> Result<Bytes, Error>;
+        #[rpc(name = "eth_signTypedData")]
+        fn sign_typed_data(&self, Hex<Address>, Value) -> Result<Bytes, Error>;
+        #[rpc(name = "eth_signTypedData_v4")]
+        fn sign_typed_data_v4(&self, Hex<Address>, Value) -> Result<Bytes, Error>;
         #[rpc(name = "eth_sendTransaction")]
         fn send_transaction(&self, RPCTransaction) -> Result<Hex<H256>, Error>;
         #[rpc(name = "eth_sendRawTransaction")]
         fn send_raw_transaction(&self, Bytes) -> Result<Hex<H256>, Error>;
 
-        #[rpc(name = "eth_call")]
-        fn call(&self, RPCTransaction, Trailing<String>) -> Result<Bytes, Error>;
         #[rpc(name = "eth_estimateGas")]
         fn estimate_gas(&self, RPCTransaction, Trailing<String>) -> Result<Hex<Gas>, Error>;
 
@@ -317,18 +374,143 @@ build_rpc_trait! {
     }
 }
 
+build_rpc_trait! {
+    pub trait PersonalRPC {
+        #[rpc(name = "personal_newAccount")]
+        fn new_account(&self, String) -> Result<Hex<Address>, Error>;
+        #[rpc(name = "personal_importRawKey")]
+        fn import_raw_key(&self, String, String) -> Result<Hex<Address>, Error>;
+        #[rpc(name = "personal_listAccounts")]
+        fn list_accounts(&self) -> Result<Vec<Hex<Address>>, Error>;
+        #[rpc(name = "personal_unlockAccount")]
+        fn unlock_account(&self, Hex<Address>, String, Trailing<u64>) -> Result<bool, Error>;
+        #[rpc(name = "personal_lockAccount")]
+        fn lock_account(&self, Hex<Address>) -> Result<bool, Error>;
+        #[rpc(name = "personal_sendTransaction")]
+        fn send_transaction(&self, RPCTransaction, String) -> Result<Hex<H256>, Error>;
+        #[rpc(name = "personal_sign")]
+        fn sign(&self, Bytes, Hex<Address>, String) -> Result<Bytes, Error>;
+        #[rpc(name = "personal_ecRecover")]
+        fn ec_recover(&self, Bytes, Bytes) -> Result<Hex<Address>, Error>;
+    }
+}
+
+build_rpc_trait! {
+    pub trait SvmRPC {
+        #[rpc(name = "svm_mine")]
+        fn mine(&self) -> Result<Hex<usize>, Error>;
+        #[rpc(name = "svm_getMiningMode")]
+        fn mining_mode(&self) -> Result<String, Error>;
+        #[rpc(name = "svm_setMiningMode")]
+        fn set_mining_mode(&self, String) -> Result<bool, Error>;
+        #[rpc(name = "svm_getTransactionFailure")]
+        fn transaction_failure(&self, Hex<H256>) -> Result<Option<String>, Error>;
+        #[rpc(name = "svm_exportState")]
+        fn export_state(&self, String, Trailing<String>) -> Result<bool, Error>;
+        #[rpc(name = "svm_setBalance")]
+        fn set_balance(&self, Hex<Address>, Hex<U256>) -> Result<bool, Error>;
+        #[rpc(name = "svm_setCode")]
+        fn set_code(&self, Hex<Address>, Bytes) -> Result<bool, Error>;
+        #[rpc(name = "svm_setNonce")]
+        fn set_nonce(&self, Hex<Address>, Hex<U256>) -> Result<bool, Error>;
+        #[rpc(name = "svm_setStorageAt")]
+        fn set_storage_at(&self, Hex<Address>, Hex<U256>, Hex<U256>) -> Result<bool, Error>;
+        #[rpc(name = "svm_impersonateAccount")]
+        fn impersonate_account(&self, Hex<Address>) -> Result<bool, Error>;
+        #[rpc(name = "svm_stopImpersonatingAccount")]
+        fn stop_impersonating_account(&self, Hex<Address>) -> Result<bool, Error>;
+    }
+}
+
+build_rpc_trait! {
+    pub trait EvmRPC {
+        #[rpc(name = "evm_mine")]
+        fn mine(&self, Trailing<u64>) -> Result<String, Error>;
+        #[rpc(name = "evm_increaseTime")]
+        fn increase_time(&self, u64) -> Result<i64, Error>;
+        #[rpc(name = "evm_setNextBlockTimestamp")]
+        fn set_next_block_timestamp(&self, u64) -> Result<bool, Error>;
+        #[rpc(name = "evm_snapshot")]
+        fn snapshot(&self) -> Result<Hex<usize>, Error>;
+        #[rpc(name = "evm_revert")]
+        fn revert(&self, Hex<U256>) -> Result<bool, Error>;
+    }
+}
+
+/// Write the accounts after the given block to a file, in the format of
+/// `debug_dumpBlock`, as `svm_exportState` does.
+pub fn write_state_file(state: &MinerState, number: usize, path: &Path) -> Result<(), Error> {
+    let dump = util::to_rpc_dump(state, number)?;
+    serde_json::to_writer_pretty(File::create(path)?, &dump)
+        .map_err(|err| Error::IOError(format!("{}", err)))
+}
+
+/// Read a state dump written by `svm_exportState`, to be used as the
+/// genesis of a new chain.
+pub fn read_state_file(path: &Path) -> Result<Genesis, Error> {
+    let dump: RPCDump = serde_json::from_reader(File::open(path)?)
+        .map_err(|err| Error::InvalidParams(format!("{}: {}", path.display(), err)))?;
+    Ok(util::from_rpc_dump(dump))
+}
+
+/// Parse an optional parameter, naming it in the error if it is malformed.
+fn parse_param<T: DeserializeOwned>(name: &str, value: Option<Value>) -> Result<Option<T>, Error> {
+    match value {
+        None | Some(Value::Null) => Ok(None),
+        Some(value) => serde_json::from_value(value).map(Some)
+            .map_err(|err| Error::InvalidParams(format!("{}: {}", name, err))),
+    }
+}
+
+/// `eth_call` takes the transaction followed by an optional block, state
+/// overrides and block overrides. `build_rpc_trait!` only allows one
+/// trailing parameter, so its parameters are parsed here.
+fn eth_call<P: Patch + Send>(
+    rpc: &serves::MinerEthereumRPC<P>, params: Params
+) -> Result<Value, jsonrpc_core::Error> {
+    let mut params = match params {
+        Params::Array(params) => params.into_iter(),
+        _ => return Err(Error::InvalidParams("expected an array of parameters".to_string()).into()),
+    };
+    if params.len() > 4 {
+        return Err(Error::InvalidParams(format!("expected at most 4 parameters, got {}", params.len())).into());
+    }
+
+    let result = parse_param("transaction", params.next())
+        .and_then(|transaction: Option<RPCTransaction>| {
+            let transaction = transaction.ok_or_else(|| Error::InvalidParams("missing transaction".to_string()))?;
+            let block = parse_param("block", params.next())?;
+            let state_overrides = parse_param("state overrides", params.next())?;
+            let block_overrides = parse_param("block overrides", params.next())?;
+            rpc.call(transaction, block, state_overrides, block_overrides)
+        });
+
+    match result {
+        Ok(bytes) => Ok(serde_json::to_value(bytes).unwrap()),
+        Err(err) => Err(err.into()),
+    }
+}
+
 pub fn rpc_loop<P: 'static + Patch + Send>(
     state: Arc<Mutex<MinerState>>, addr: &SocketAddr, channel: Sender<bool>
 ) {
-    let rpc = serves::MinerEthereumRPC::<P>::new(state.clone(), channel);
+    let rpc = serves::MinerEthereumRPC::<P>::new(state.clone());
+    let call = serves::MinerEthereumRPC::<P>::new(state.clone());
     let filter = serves::MinerFilterRPC::<P>::new(state.clone());
-    let debug = serves::MinerDebugRPC::<P>::new(state);
+    let debug = serves::MinerDebugRPC::<P>::new(state.clone());
+    let evm = serves::MinerEvmRPC::<P>::new(state.clone());
+    let personal = serves::MinerPersonalRPC::<P>::new(state.clone());
+    let svm = serves::MinerSvmRPC::<P>::new(state, channel);
 
     let mut io = IoHandler::default();
 
     io.extend_with(rpc.to_delegate());
     io.extend_with(filter.to_delegate());
     io.extend_with(debug.to_delegate());
+    io.extend_with(evm.to_delegate());
+    io.extend_with(personal.to_delegate());
+    io.extend_with(svm.to_delegate());
+    io.add_method("eth_call", move |params: Params| eth_call(&call, params));
 
     let server = ServerBuilder::new(io)
         .cors(DomainsValidation::AllowOnly(vec![
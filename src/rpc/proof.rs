@@ -0,0 +1,219 @@
+use bigint::H256;
+use rlp::UntrustedRlp;
+use sha3::{Digest, Keccak256};
+use trie::DatabaseGuard;
+
+use error::Error;
+
+fn keccak(data: &[u8]) -> H256 {
+    H256::from(Keccak256::digest(data).as_slice())
+}
+
+/// Root of an empty trie, `keccak256(rlp(""))`.
+pub fn empty_trie_root() -> H256 {
+    keccak(&[0x80])
+}
+
+fn nibbles(key: &[u8]) -> Vec<u8> {
+    let mut ret = Vec::with_capacity(key.len() * 2);
+    for byte in key {
+        ret.push(byte >> 4);
+        ret.push(byte & 0x0f);
+    }
+    ret
+}
+
+/// Decode the hex-prefix encoded path of a leaf or extension node,
+/// returning its nibbles and whether the node is a leaf.
+fn decode_path(encoded: &[u8]) -> Result<(Vec<u8>, bool), Error> {
+    if encoded.is_empty() {
+        return Err(Error::RlpError("empty trie node path".to_string()));
+    }
+
+    let flag = encoded[0] >> 4;
+    let mut ret = Vec::new();
+    if flag & 1 == 1 {
+        ret.push(encoded[0] & 0x0f);
+    }
+    ret.extend(nibbles(&encoded[1..]));
+    Ok((ret, flag & 2 == 2))
+}
+
+/// Nodes on the path from `root` to `key` in a secure trie, which is keyed
+/// by `keccak256(key)`. The path ends where the key is found or shown to
+/// be absent. Nodes embedded in their parent are not listed on their own,
+/// as in EIP-1186.
+pub fn prove<G: DatabaseGuard>(database: &G, root: H256, key: &[u8]) -> Result<Vec<Vec<u8>>, Error> {
+    let mut proof = Vec::new();
+    if root == empty_trie_root() {
+        return Ok(proof);
+    }
+
+    let key_path = nibbles(&keccak(key));
+    let mut path = &key_path[..];
+
+    let mut node = database.get(root).ok_or(Error::NotFound)?;
+    proof.push(node.clone());
+
+    loop {
+        let next = {
+            let rlp = UntrustedRlp::new(&node);
+            let child = match rlp.item_count()? {
+                2 => {
+                    let (node_path, is_leaf) = decode_path(rlp.at(0)?.data()?)?;
+                    if is_leaf || !path.starts_with(&node_path) {
+                        break;
+                    }
+                    path = &path[node_path.len()..];
+                    rlp.at(1)?
+                },
+                17 => {
+                    if path.is_empty() {
+                        break;
+                    }
+                    let child = rlp.at(path[0] as usize)?;
+                    path = &path[1..];
+                    child
+                },
+                _ => return Err(Error::RlpError("invalid trie node".to_string())),
+            };
+
+            if child.is_empty() {
+                break;
+            } else if child.is_list() {
+                child.as_raw().to_vec()
+            } else {
+                let child = database.get(H256::from(child.data()?)).ok_or(Error::NotFound)?;
+                proof.push(child.clone());
+                child
+            }
+        };
+        node = next;
+    }
+
+    Ok(proof)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bigint::{Address, U256};
+    use block::Account;
+    use rlp;
+    use trie::{MemoryDatabase, Database};
+
+    /// Follow a proof from `root` along `keccak256(key)`, checking that
+    /// every node is the one its parent refers to. Returns the value
+    /// found, or `None` if the proof shows the key is absent.
+    fn verify(root: H256, key: &[u8], proof: &[Vec<u8>]) -> Option<Vec<u8>> {
+        let key_path = nibbles(&keccak(key));
+        let mut path = &key_path[..];
+
+        let mut nodes = proof.iter();
+        let mut node = nodes.next().unwrap().clone();
+        assert_eq!(keccak(&node), root);
+
+        let value = loop {
+            let next = {
+                let rlp = UntrustedRlp::new(&node);
+                let child = if rlp.item_count().unwrap() == 2 {
+                    let (node_path, is_leaf) = decode_path(rlp.at(0).unwrap().data().unwrap()).unwrap();
+                    if is_leaf {
+                        if path == &node_path[..] {
+                            break Some(rlp.at(1).unwrap().data().unwrap().to_vec());
+                        }
+                        break None;
+                    }
+                    if !path.starts_with(&node_path) {
+                        break None;
+                    }
+                    path = &path[node_path.len()..];
+                    rlp.at(1).unwrap()
+                } else {
+                    let child = rlp.at(path[0] as usize).unwrap();
+                    path = &path[1..];
+                    child
+                };
+
+                if child.is_empty() {
+                    break None;
+                } else if child.is_list() {
+                    child.as_raw().to_vec()
+                } else {
+                    let next = nodes.next().unwrap().clone();
+                    assert_eq!(keccak(&next), H256::from(child.data().unwrap()));
+                    next
+                }
+            };
+            node = next;
+        };
+
+        assert!(nodes.next().is_none());
+        value
+    }
+
+    fn slot(index: u8) -> H256 {
+        H256::from(U256::from(index as u64))
+    }
+
+    fn address(index: u8) -> Address {
+        Address::from(&[index; 20][..])
+    }
+
+    #[test]
+    fn account_and_storage_proofs() {
+        let database = MemoryDatabase::default();
+
+        let storage_root = {
+            let mut trie = database.create_empty();
+            for index in 0..32 {
+                trie.insert_raw(keccak(&slot(index)).to_vec(), rlp::encode(&U256::from(index as u64 + 1)).to_vec());
+            }
+            trie.root()
+        };
+        let account = Account {
+            nonce: U256::one(),
+            balance: U256::from(1000u64),
+            storage_root,
+            code_hash: keccak(&[]),
+        };
+
+        let root = {
+            let mut trie = database.create_empty();
+            for index in 0..32 {
+                let account = Account { nonce: U256::from(index as u64), ..account.clone() };
+                trie.insert_raw(keccak(&address(index)).to_vec(), rlp::encode(&account).to_vec());
+            }
+            trie.root()
+        };
+
+        let guard = database.create_guard();
+
+        let proof = prove(&guard, root, &address(7)).unwrap();
+        let value = verify(root, &address(7), &proof).unwrap();
+        let proven: Account = UntrustedRlp::new(&value).as_val().unwrap();
+        assert_eq!(proven.nonce, U256::from(7u64));
+        assert_eq!(proven.storage_root, storage_root);
+
+        for index in 0..32 {
+            let proof = prove(&guard, proven.storage_root, &slot(index)).unwrap();
+            assert_eq!(verify(proven.storage_root, &slot(index), &proof),
+                       Some(rlp::encode(&U256::from(index as u64 + 1)).to_vec()));
+        }
+
+        let proof = prove(&guard, root, &address(100)).unwrap();
+        assert_eq!(verify(root, &address(100), &proof), None);
+        let proof = prove(&guard, storage_root, &slot(100)).unwrap();
+        assert_eq!(verify(storage_root, &slot(100), &proof), None);
+
+        assert!(prove(&guard, empty_trie_root(), &address(7)).unwrap().is_empty());
+    }
+
+    #[test]
+    fn decode_hex_prefix() {
+        assert_eq!(decode_path(&[0x11, 0x23, 0x45]).unwrap(), (vec![1, 2, 3, 4, 5], false));
+        assert_eq!(decode_path(&[0x00, 0x01, 0x23]).unwrap(), (vec![0, 1, 2, 3], false));
+        assert_eq!(decode_path(&[0x20, 0x0f, 0x1c]).unwrap(), (vec![0, 0x0f, 1, 0x0c], true));
+        assert_eq!(decode_path(&[0x3f, 0x1c]).unwrap(), (vec![0x0f, 1, 0x0c], true));
+    }
+}
@@ -1,11 +1,12 @@
-use super::{EthereumRPC, FilterRPC, DebugRPC, Either, RPCTransaction, RPCTrace, RPCStep, RPCBlock, RPCLog, RPCReceipt, RPCLogFilter, RPCBlockTrace, RPCDump, RPCDumpAccount, RPCTraceConfig};
+use super::{EthereumRPC, FilterRPC, DebugRPC, EvmRPC, PersonalRPC, SvmRPC, Either, RPCTransaction, RPCAccountOverride, RPCBlockOverrides, RPCTrace, RPCBlock, RPCLog, RPCReceipt, RPCLogFilter, RPCBlockTrace, RPCDump, RPCProof, RPCTraceConfig};
 use super::util::*;
 use super::filter::*;
 use super::serialize::*;
 use super::solidity::*;
+use super::typed_data::TypedData;
 
 use error::Error;
-use miner::MinerState;
+use miner::{self, MinerState, MiningMode, ChainStateful};
 
 use rlp::{self, UntrustedRlp};
 use bigint::{M256, U256, H256, H2048, Address, Gas};
@@ -14,18 +15,22 @@ use block::{Block, TotalHeader, Account, Log, Receipt, FromKey, Transaction, Uns
 use trie::{Database, DatabaseGuard, FixedSecureTrie};
 use blockchain::chain::HeaderHash;
 use sputnikvm::{AccountChange, ValidTransaction, SeqTransactionVM, VM, VMStatus, Memory, MachineStatus, HeaderParams, Patch};
-use sputnikvm_stateful::MemoryStateful;
 use std::str::FromStr;
 use std::sync::{Arc, Mutex};
 use std::sync::mpsc::{channel, Sender, Receiver};
 use std::collections::HashMap;
 use std::marker::PhantomData;
+use std::fs::File;
+use std::path::Path;
+use secp256k1::SECP256K1;
+use secp256k1::key::SecretKey;
+use rand::os::OsRng;
+use serde_json::Value;
 
 use jsonrpc_macros::Trailing;
 
 pub struct MinerEthereumRPC<P: Patch + Send> {
     state: Arc<Mutex<MinerState>>,
-    channel: Sender<bool>,
     _patch: PhantomData<P>,
 }
 
@@ -39,18 +44,66 @@ pub struct MinerDebugRPC<P: Patch + Send> {
     _patch: PhantomData<P>,
 }
 
+pub struct MinerEvmRPC<P: Patch + Send> {
+    state: Arc<Mutex<MinerState>>,
+    _patch: PhantomData<P>,
+}
+
+pub struct MinerPersonalRPC<P: Patch + Send> {
+    state: Arc<Mutex<MinerState>>,
+    _patch: PhantomData<P>,
+}
+
+pub struct MinerSvmRPC<P: Patch + Send> {
+    state: Arc<Mutex<MinerState>>,
+    channel: Sender<bool>,
+    _patch: PhantomData<P>,
+}
+
 unsafe impl<P: Patch + Send> Sync for MinerEthereumRPC<P> { }
 unsafe impl<P: Patch + Send> Sync for MinerFilterRPC<P> { }
 unsafe impl<P: Patch + Send> Sync for MinerDebugRPC<P> { }
+unsafe impl<P: Patch + Send> Sync for MinerEvmRPC<P> { }
+unsafe impl<P: Patch + Send> Sync for MinerPersonalRPC<P> { }
+unsafe impl<P: Patch + Send> Sync for MinerSvmRPC<P> { }
 
 impl<P: Patch + Send> MinerEthereumRPC<P> {
-    pub fn new(state: Arc<Mutex<MinerState>>, channel: Sender<bool>) -> Self {
+    pub fn new(state: Arc<Mutex<MinerState>>) -> Self {
         MinerEthereumRPC {
-            channel,
             state,
             _patch: PhantomData,
         }
     }
+
+    /// `eth_call`, run on a scratch copy of the state with the given
+    /// account and block overrides applied.
+    pub fn call(
+        &self, transaction: RPCTransaction, block: Option<String>,
+        state_overrides: Option<HashMap<Hex<Address>, RPCAccountOverride>>,
+        block_overrides: Option<RPCBlockOverrides>
+    ) -> Result<Bytes, Error> {
+        let state = self.state.lock().unwrap();
+
+        let number = from_block_number(&state, block)?;
+        let block = state.get_block_by_number(number)?;
+
+        let mut stateful = state.stateful_at(state.state_root_at(number)?);
+        if let Some(state_overrides) = state_overrides {
+            apply_state_overrides(&state, number, &mut stateful, state_overrides)?;
+        }
+        let valid = to_valid_transaction::<P>(&state, stateful.root(), number, transaction)?;
+
+        let mut header = to_call_header_params(&state, &block);
+        if let Some(block_overrides) = block_overrides {
+            apply_block_overrides(&mut header, block_overrides);
+        }
+
+        let vm: SeqTransactionVM<P> = miner::call(
+            &mut stateful, &state.fork(), valid, &header,
+            &state.get_last_256_block_hashes_by_number(number)?);
+
+        Ok(Bytes(call_output(&vm)?))
+    }
 }
 
 impl<P: Patch + Send> MinerFilterRPC<P> {
@@ -71,6 +124,34 @@ impl<P: Patch + Send> MinerDebugRPC<P> {
     }
 }
 
+impl<P: Patch + Send> MinerEvmRPC<P> {
+    pub fn new(state: Arc<Mutex<MinerState>>) -> Self {
+        MinerEvmRPC {
+            state,
+            _patch: PhantomData,
+        }
+    }
+}
+
+impl<P: Patch + Send> MinerPersonalRPC<P> {
+    pub fn new(state: Arc<Mutex<MinerState>>) -> Self {
+        MinerPersonalRPC {
+            state,
+            _patch: PhantomData,
+        }
+    }
+}
+
+impl<P: Patch + Send> MinerSvmRPC<P> {
+    pub fn new(state: Arc<Mutex<MinerState>>, channel: Sender<bool>) -> Self {
+        MinerSvmRPC {
+            channel,
+            state,
+            _patch: PhantomData,
+        }
+    }
+}
+
 impl<P: 'static + Patch + Send> EthereumRPC for MinerEthereumRPC<P> {
     fn client_version(&self) -> Result<String, Error> {
         Ok("sputnikvm-dev/v0.1".to_string())
@@ -82,7 +163,9 @@ impl<P: 'static + Patch + Send> EthereumRPC for MinerEthereumRPC<P> {
     }
 
     fn network_id(&self) -> Result<String, Error> {
-        Ok(format!("{}", 1))
+        let state = self.state.lock().unwrap();
+
+        Ok(format!("{}", state.network_id()))
     }
 
     fn is_listening(&self) -> Result<bool, Error> {
@@ -101,12 +184,22 @@ impl<P: 'static + Patch + Send> EthereumRPC for MinerEthereumRPC<P> {
         Ok(false)
     }
 
+    fn chain_id(&self) -> Result<Hex<u64>, Error> {
+        let state = self.state.lock().unwrap();
+
+        Ok(Hex(state.chain_id()))
+    }
+
     fn coinbase(&self) -> Result<Hex<Address>, Error> {
-        Ok(Hex(Address::default()))
+        let state = self.state.lock().unwrap();
+
+        Ok(Hex(state.coinbase()))
     }
 
     fn is_mining(&self) -> Result<bool, Error> {
-        Ok(true)
+        let state = self.state.lock().unwrap();
+
+        Ok(state.mining_mode() != MiningMode::Manual)
     }
 
     fn hashrate(&self) -> Result<String, Error> {
@@ -120,9 +213,7 @@ impl<P: 'static + Patch + Send> EthereumRPC for MinerEthereumRPC<P> {
     fn accounts(&self) -> Result<Vec<Hex<Address>>, Error> {
         let state = self.state.lock().unwrap();
 
-        Ok(state.accounts().iter().map(|key| {
-            Address::from_secret_key(key).unwrap()
-        }).map(|address| {
+        Ok(state.accounts().into_iter().map(|address| {
             Hex(address)
         }).collect())
     }
@@ -138,12 +229,9 @@ impl<P: 'static + Patch + Send> EthereumRPC for MinerEthereumRPC<P> {
 
         let block = from_block_number(&state, block)?;
 
-        let block = state.get_block_by_number(block);
-        let stateful = state.stateful();
-        let trie = stateful.state_of(block.header.state_root);
+        let root = state.state_root_at(block)?;
 
-        let account: Option<Account> = trie.get(&address.0);
-        match account {
+        match state.account_at(root, block, address.0)? {
             Some(account) => {
                 Ok(Hex(account.balance))
             },
@@ -158,34 +246,24 @@ impl<P: 'static + Patch + Send> EthereumRPC for MinerEthereumRPC<P> {
 
         let block = from_block_number(&state, block)?;
 
-        let block = state.get_block_by_number(block);
-        let stateful = state.stateful();
-        let trie = stateful.state_of(block.header.state_root);
+        let root = state.state_root_at(block)?;
 
-        let account: Option<Account> = trie.get(&address.0);
-        match account {
-            Some(account) => {
-                let storage = stateful.storage_state_of(account.storage_root);
-                let value = storage.get(&H256::from(index.0)).unwrap_or(M256::zero());
-                Ok(Hex(value))
-            },
-            None => {
-                Ok(Hex(M256::zero()))
-            },
-        }
+        Ok(Hex(state.storage_at(root, block, address.0, index.0)?))
     }
 
     fn transaction_count(&self, address: Hex<Address>, block: Trailing<String>) -> Result<Hex<U256>, Error> {
         let state = self.state.lock().unwrap();
 
+        let block: Option<String> = block.into();
+        if block == Some("pending".to_string()) {
+            return Ok(Hex(state.pending_nonce(address.0)));
+        }
+
         let block = from_block_number(&state, block)?;
 
-        let block = state.get_block_by_number(block);
-        let stateful = state.stateful();
-        let trie = stateful.state_of(block.header.state_root);
+        let root = state.state_root_at(block)?;
 
-        let account: Option<Account> = trie.get(&address.0);
-        match account {
+        match state.account_at(root, block, address.0)? {
             Some(account) => {
                 Ok(Hex(account.nonce))
             },
@@ -215,7 +293,7 @@ impl<P: 'static + Patch + Send> EthereumRPC for MinerEthereumRPC<P> {
             Err(Error::NotFound) => return Ok(None),
             Err(e) => return Err(e.into()),
         };
-        let block = state.get_block_by_number(number);
+        let block = state.get_block_by_number(number)?;
 
         Ok(Some(Hex(block.transactions.len())))
     }
@@ -240,7 +318,7 @@ impl<P: 'static + Patch + Send> EthereumRPC for MinerEthereumRPC<P> {
             Err(Error::NotFound) => return Ok(None),
             Err(e) => return Err(e.into()),
         };
-        let block = state.get_block_by_number(number);
+        let block = state.get_block_by_number(number)?;
 
         Ok(Some(Hex(block.ommers.len())))
     }
@@ -250,14 +328,11 @@ impl<P: 'static + Patch + Send> EthereumRPC for MinerEthereumRPC<P> {
 
         let block = from_block_number(&state, block)?;
 
-        let block = state.get_block_by_number(block);
-        let stateful = state.stateful();
-        let trie = stateful.state_of(block.header.state_root);
+        let root = state.state_root_at(block)?;
 
-        let account: Option<Account> = trie.get(&address.0);
-        match account {
+        match state.account_at(root, block, address.0)? {
             Some(account) => {
-                Ok(Bytes(stateful.code(account.code_hash).unwrap()))
+                Ok(Bytes(account.code))
             },
             None => {
                 Ok(Bytes(Vec::new()))
@@ -265,54 +340,64 @@ impl<P: 'static + Patch + Send> EthereumRPC for MinerEthereumRPC<P> {
         }
     }
 
+    fn proof(&self, address: Hex<Address>, keys: Vec<Hex<U256>>, block: Trailing<String>) -> Result<RPCProof, Error> {
+        let state = self.state.lock().unwrap();
+
+        let block = from_block_number(&state, block)?;
+
+        // Changes made since the block was mined, e.g. by `svm_setBalance`,
+        // are not in its state root, so prove against the header.
+        let root = state.get_block_by_number(block)?.header.state_root;
+
+        to_rpc_proof(&state, root, address.0, keys.into_iter().map(|key| key.0).collect())
+    }
+
     fn sign(&self, address: Hex<Address>, message: Bytes) -> Result<Bytes, Error> {
-        use sha3::{Digest, Keccak256};
-        use secp256k1::{SECP256K1, Message};
+        let state = self.state.lock().unwrap();
+
+        let secret_key = state.secret_key(address.0)?;
+        Ok(Bytes(sign_hash(personal_message_hash(&message.0), &secret_key)?))
+    }
 
+    fn sign_typed_data(&self, address: Hex<Address>, data: Value) -> Result<Bytes, Error> {
         let state = self.state.lock().unwrap();
 
-        let mut signing_message = Vec::new();
+        let typed_data = TypedData::from_value(data)?;
+        let secret_key = state.secret_key(address.0)?;
+        Ok(Bytes(sign_hash(typed_data.signing_hash()?, &secret_key)?))
+    }
 
-        signing_message.extend("Ethereum Signed Message:\n".as_bytes().iter().cloned());
-        signing_message.extend(format!("0x{:x}\n", message.0.len()).as_bytes().iter().cloned());
-        signing_message.extend(message.0.iter().cloned());
+    fn sign_typed_data_v4(&self, address: Hex<Address>, data: Value) -> Result<Bytes, Error> {
+        self.sign_typed_data(address, data)
+    }
 
-        let hash = H256::from(Keccak256::digest(&signing_message).as_slice());
-        let secret_key = {
-            let mut secret_key = None;
-            for key in state.accounts() {
-                if Address::from_secret_key(&key)? == address.0 {
-                    secret_key = Some(key);
-                }
-            }
-            match secret_key {
-                Some(val) => val,
-                None => return Err(Error::NotFound),
-            }
-        };
-        let sign = SECP256K1.sign_recoverable(&Message::from_slice(&hash).unwrap(), &secret_key)?;
-        let (rec, sign) = sign.serialize_compact(&SECP256K1);
-        let mut ret = Vec::new();
-        ret.push(rec.to_i32() as u8);
-        ret.extend(sign.as_ref());
+    fn sign_transaction(&self, transaction: RPCTransaction) -> Result<Bytes, Error> {
+        let state = self.state.lock().unwrap();
 
-        Ok(Bytes(ret))
+        // Impersonated accounts have no key, so only unlocked accounts can
+        // sign.
+        let address = transaction.from.as_ref().map(|from| from.0).unwrap_or(Address::default());
+        let secret_key = state.secret_key(address)?;
+        let transaction = to_signed_transaction_with_key(&state, transaction, &secret_key)?;
+        Ok(Bytes(rlp::encode(&transaction).to_vec()))
     }
 
     fn send_transaction(&self, transaction: RPCTransaction) -> Result<Hex<H256>, Error> {
         let mut state = self.state.lock().unwrap();
 
-
-        let (valid, transaction) = {
-            let stateful = state.stateful();
-            let transaction = to_signed_transaction(&state, transaction, &stateful)?;
-            let valid = stateful.to_valid::<P>(transaction.clone())?;
-
-            (valid, transaction)
+        let address = transaction.from.as_ref().map(|from| from.0).unwrap_or(Address::default());
+        let hash = if state.is_impersonated(address) {
+            let transaction = to_impersonated_transaction(&state, address, transaction);
+            validate_transaction::<P>(&state, address, &transaction)?;
+            state.append_unsigned_transaction(address, transaction)?
+        } else {
+            let transaction = to_signed_transaction(&state, transaction)?;
+            validate_transaction::<P>(&state, address, &transaction)?;
+            state.append_pending_transaction(transaction)?
         };
-
-        let hash = state.append_pending_transaction(transaction);
-        self.channel.send(true);
+        if state.mining_mode() == MiningMode::Automine {
+            miner::mine_transaction::<P>(&mut state, address, hash);
+        }
         Ok(Hex(hash))
     }
 
@@ -321,49 +406,34 @@ impl<P: 'static + Patch + Send> EthereumRPC for MinerEthereumRPC<P> {
 
         let rlp = UntrustedRlp::new(&data.0);
         let transaction: Transaction = rlp.as_val()?;
+        let caller = transaction.caller().map_err(|_| Error::ECDSAError)?;
+        validate_transaction::<P>(&state, caller, &transaction)?;
 
-        {
-            let stateful = state.stateful();
-            stateful.to_valid::<P>(transaction.clone())?;
+        let hash = state.append_pending_transaction(transaction)?;
+        if state.mining_mode() == MiningMode::Automine {
+            miner::mine_transaction::<P>(&mut state, caller, hash);
         }
-
-        let hash = state.append_pending_transaction(transaction);
-        self.channel.send(true);
         Ok(Hex(hash))
     }
 
-    fn call(&self, transaction: RPCTransaction, block: Trailing<String>) -> Result<Bytes, Error> {
+    fn estimate_gas(&self, mut transaction: RPCTransaction, block: Trailing<String>) -> Result<Hex<Gas>, Error> {
         let state = self.state.lock().unwrap();
 
-        let stateful = state.stateful();
-
-        let valid = to_valid_transaction::<P>(&state, transaction, &stateful)?;
-        let block = from_block_number(&state, block)?;
+        let number = from_block_number(&state, block)?;
+        let block = state.get_block_by_number(number)?;
+        let root = state.state_root_at(number)?;
 
-        let block = state.get_block_by_number(block);
-
-        let vm: SeqTransactionVM<P> = stateful.call(
-            valid, HeaderParams::from(&block.header),
-            &state.get_last_256_block_hashes());
-
-        Ok(Bytes(vm.out().into()))
-    }
-
-    fn estimate_gas(&self, transaction: RPCTransaction, block: Trailing<String>) -> Result<Hex<Gas>, Error> {
-        let state = self.state.lock().unwrap();
-
-        let stateful = state.stateful();
-
-        let valid = to_valid_transaction::<P>(&state, transaction, &stateful)?;
-        let block = from_block_number(&state, block)?;
-
-        let block = state.get_block_by_number(block);
-
-        let vm: SeqTransactionVM<P> = stateful.call(
-            valid, HeaderParams::from(&block.header),
-            &state.get_last_256_block_hashes());
+        let cap = match transaction.gas {
+            Some(ref gas) => gas.0,
+            None => block.header.gas_limit,
+        };
+        // Validate with the cap, not the default gas limit of `eth_call`.
+        transaction.gas = Some(Hex(cap));
+        let valid = to_valid_transaction::<P>(&state, root, number, transaction)?;
 
-        Ok(Hex(vm.used_gas()))
+        Ok(Hex(estimate_gas::<P>(
+            &state, root, valid, cap,
+            &to_call_header_params(&state, &block), &state.get_last_256_block_hashes_by_number(number)?)?))
     }
 
     fn block_by_hash(&self, hash: Hex<H256>, full: bool) -> Result<Option<RPCBlock>, Error> {
@@ -380,7 +450,7 @@ impl<P: 'static + Patch + Send> EthereumRPC for MinerEthereumRPC<P> {
             Err(e) => return Err(e.into()),
         };
 
-        Ok(Some(to_rpc_block(block, total, full)))
+        Ok(Some(to_rpc_block(&state, block, total, full)))
     }
 
     fn block_by_number(&self, number: String, full: bool) -> Result<Option<RPCBlock>, Error> {
@@ -391,14 +461,14 @@ impl<P: 'static + Patch + Send> EthereumRPC for MinerEthereumRPC<P> {
             Err(Error::NotFound) => return Ok(None),
             Err(e) => return Err(e.into()),
         };
-        let block = state.get_block_by_number(number);
+        let block = state.get_block_by_number(number)?;
         let total = match state.get_total_header_by_hash(block.header.header_hash()) {
             Ok(val) => val,
             Err(Error::NotFound) => return Ok(None),
             Err(e) => return Err(e.into()),
         };
 
-        Ok(Some(to_rpc_block(block, total, full)))
+        Ok(Some(to_rpc_block(&state, block, total, full)))
     }
 
     fn transaction_by_hash(&self, hash: Hex<H256>) -> Result<Option<RPCTransaction>, Error> {
@@ -414,7 +484,7 @@ impl<P: 'static + Patch + Send> EthereumRPC for MinerEthereumRPC<P> {
             Err(_) => None,
         };
 
-        Ok(Some(to_rpc_transaction(transaction, block.as_ref())))
+        Ok(Some(to_rpc_transaction(&state, transaction, block.as_ref())))
     }
 
     fn transaction_by_block_hash_and_index(&self, block_hash: Hex<H256>, index: Hex<U256>) -> Result<Option<RPCTransaction>, Error> {
@@ -430,7 +500,7 @@ impl<P: 'static + Patch + Send> EthereumRPC for MinerEthereumRPC<P> {
         }
         let transaction = block.transactions[index.0.as_usize()].clone();
 
-        Ok(Some(to_rpc_transaction(transaction, Some(&block))))
+        Ok(Some(to_rpc_transaction(&state, transaction, Some(&block))))
     }
 
     fn transaction_by_block_number_and_index(&self, number: String, index: Hex<U256>) -> Result<Option<RPCTransaction>, Error> {
@@ -441,13 +511,13 @@ impl<P: 'static + Patch + Send> EthereumRPC for MinerEthereumRPC<P> {
             Err(Error::NotFound) => return Ok(None),
             Err(e) => return Err(e.into()),
         };
-        let block = state.get_block_by_number(number);
+        let block = state.get_block_by_number(number)?;
         if index.0.as_usize() >= block.transactions.len() {
             return Ok(None);
         }
         let transaction = block.transactions[index.0.as_usize()].clone();
 
-        Ok(Some(to_rpc_transaction(transaction, Some(&block))))
+        Ok(Some(to_rpc_transaction(&state, transaction, Some(&block))))
     }
 
     fn transaction_receipt(&self, hash: Hex<H256>) -> Result<Option<RPCReceipt>, Error> {
@@ -499,7 +569,7 @@ impl<P: 'static + Patch + Send> EthereumRPC for MinerEthereumRPC<P> {
             Err(e) => return Err(e.into()),
         };
 
-        Ok(Some(to_rpc_block(uncle, total, false)))
+        Ok(Some(to_rpc_block(&state, uncle, total, false)))
     }
 
     fn uncle_by_block_number_and_index(&self, block_number: String, index: Hex<U256>) -> Result<Option<RPCBlock>, Error> {
@@ -511,7 +581,7 @@ impl<P: 'static + Patch + Send> EthereumRPC for MinerEthereumRPC<P> {
             Err(e) => return Err(e.into()),
         };
         let index = index.0.as_usize();
-        let block = state.get_block_by_number(block_number);
+        let block = state.get_block_by_number(block_number)?;
         let uncle_hash = block.ommers[index].header_hash();
         let uncle = match state.get_block_by_hash(uncle_hash) {
             Ok(val) => val,
@@ -524,7 +594,7 @@ impl<P: 'static + Patch + Send> EthereumRPC for MinerEthereumRPC<P> {
             Err(e) => return Err(e.into()),
         };
 
-        Ok(Some(to_rpc_block(uncle, total, false)))
+        Ok(Some(to_rpc_block(&state, uncle, total, false)))
     }
 
     fn compilers(&self) -> Result<Vec<String>, Error> {
@@ -560,18 +630,21 @@ impl<P: 'static + Patch + Send> FilterRPC for MinerFilterRPC<P> {
     }
 
     fn uninstall_filter(&self, id: String) -> Result<bool, Error> {
-        let id = U256::from_str(&id)?.as_usize();
+        let id = match to_filter_id(&id)? {
+            Some(id) => id,
+            None => return Ok(false),
+        };
         self.filter.lock().unwrap().uninstall_filter(id);
         Ok(true)
     }
 
     fn filter_changes(&self, id: String) -> Result<Either<Vec<String>, Vec<RPCLog>>, Error> {
-        let id = U256::from_str(&id)?.as_usize();
+        let id = to_filter_id(&id)?.ok_or(Error::NotFound)?;
         Ok(self.filter.lock().unwrap().get_changes(id)?)
     }
 
     fn filter_logs(&self, id: String) -> Result<Vec<RPCLog>, Error> {
-        let id = U256::from_str(&id)?.as_usize();
+        let id = to_filter_id(&id)?.ok_or(Error::NotFound)?;
         Ok(self.filter.lock().unwrap().get_logs(id)?)
     }
 }
@@ -584,7 +657,7 @@ impl<P: 'static + Patch + Send> DebugRPC for MinerDebugRPC<P> {
             return Err(Error::NotFound);
         }
 
-        let block = state.get_block_by_number(number);
+        let block = state.get_block_by_number(number)?;
         Ok(Bytes(rlp::encode(&block).to_vec()))
     }
 
@@ -594,13 +667,13 @@ impl<P: 'static + Patch + Send> DebugRPC for MinerDebugRPC<P> {
 
         let transaction = state.get_transaction_by_hash(hash.0)?;
         let block = state.get_block_by_hash(state.get_transaction_block_hash_by_hash(hash.0)?)?;
-        let last_block = state.get_block_by_number(if block.header.number == U256::zero() { 0 } else { block.header.number.as_usize() - 1 });
-        let last_hashes = state.get_last_256_block_hashes_by_number(block.header.number.as_usize());
+        let last_block = state.get_block_by_number(if block.header.number == U256::zero() { 0 } else { block.header.number.as_usize() - 1 })?;
+        let last_hashes = state.get_last_256_block_hashes_by_number(block.header.number.as_usize())?;
 
-        let mut stateful: MemoryStateful<'static> = state.stateful_at(last_block.header.state_root);
+        let mut stateful: ChainStateful = state.stateful_at(last_block.header.state_root);
         for other_transaction in &block.transactions {
             if other_transaction != &transaction {
-                let valid = stateful.to_valid::<P>(transaction.clone())?;
+                let valid = miner::to_valid::<P>(&stateful, state.unsigned_senders(), transaction.clone())?;
                 let _: SeqTransactionVM<P> =
                     stateful.execute::<_, P>(valid, HeaderParams::from(&block.header), &last_hashes);
             } else {
@@ -608,7 +681,8 @@ impl<P: 'static + Patch + Send> DebugRPC for MinerDebugRPC<P> {
             }
         }
 
-        let (steps, vm) = replay_transaction::<P>(&stateful, transaction, &block, &last_hashes, &config)?;
+        let (steps, vm) = replay_transaction::<P>(&stateful, state.unsigned_senders(), transaction,
+                                                  &block, &last_hashes, &config)?;
 
         let gas = Hex(vm.used_gas());
         let return_value = Bytes(vm.out().into());
@@ -623,13 +697,13 @@ impl<P: 'static + Patch + Send> DebugRPC for MinerDebugRPC<P> {
         let config = config.unwrap_or(RPCTraceConfig::default());
         let state = self.state.lock().unwrap();
         let block: Block = UntrustedRlp::new(&block_rlp.0).as_val()?;
-        let last_block = state.get_block_by_number(if block.header.number == U256::zero() { 0 } else { block.header.number.as_usize() - 1 });
-        let last_hashes = state.get_last_256_block_hashes_by_number(block.header.number.as_usize());
+        let last_block = state.get_block_by_number(if block.header.number == U256::zero() { 0 } else { block.header.number.as_usize() - 1 })?;
+        let last_hashes = state.get_last_256_block_hashes_by_number(block.header.number.as_usize())?;
 
-        let mut stateful: MemoryStateful<'static> = state.stateful_at(last_block.header.state_root);
+        let mut stateful: ChainStateful = state.stateful_at(last_block.header.state_root);
         let mut steps = Vec::new();
         for transaction in block.transactions.clone() {
-            let (mut local_steps, vm) = replay_transaction::<P>(&stateful, transaction,
+            let (mut local_steps, vm) = replay_transaction::<P>(&stateful, state.unsigned_senders(), transaction,
                                                                 &block, &last_hashes,
                                                                 &config)?;
             steps.append(&mut local_steps);
@@ -651,14 +725,14 @@ impl<P: 'static + Patch + Send> DebugRPC for MinerDebugRPC<P> {
         if number > state.block_height() {
             return Err(Error::NotFound);
         }
-        let block: Block = state.get_block_by_number(number);
-        let last_block = state.get_block_by_number(if block.header.number == U256::zero() { 0 } else { block.header.number.as_usize() - 1 });
-        let last_hashes = state.get_last_256_block_hashes_by_number(block.header.number.as_usize());
+        let block: Block = state.get_block_by_number(number)?;
+        let last_block = state.get_block_by_number(if block.header.number == U256::zero() { 0 } else { block.header.number.as_usize() - 1 })?;
+        let last_hashes = state.get_last_256_block_hashes_by_number(block.header.number.as_usize())?;
 
-        let mut stateful: MemoryStateful<'static> = state.stateful_at(last_block.header.state_root);
+        let mut stateful: ChainStateful = state.stateful_at(last_block.header.state_root);
         let mut steps = Vec::new();
         for transaction in block.transactions.clone() {
-            let (mut local_steps, vm) = replay_transaction::<P>(&stateful, transaction,
+            let (mut local_steps, vm) = replay_transaction::<P>(&stateful, state.unsigned_senders(), transaction,
                                                                 &block, &last_hashes,
                                                                 &config)?;
             steps.append(&mut local_steps);
@@ -678,13 +752,13 @@ impl<P: 'static + Patch + Send> DebugRPC for MinerDebugRPC<P> {
         let config = config.unwrap_or(RPCTraceConfig::default());
         let state = self.state.lock().unwrap();
         let block: Block = state.get_block_by_hash(hash.0)?;
-        let last_block = state.get_block_by_number(if block.header.number == U256::zero() { 0 } else { block.header.number.as_usize() - 1 });
-        let last_hashes = state.get_last_256_block_hashes_by_number(block.header.number.as_usize());
+        let last_block = state.get_block_by_number(if block.header.number == U256::zero() { 0 } else { block.header.number.as_usize() - 1 })?;
+        let last_hashes = state.get_last_256_block_hashes_by_number(block.header.number.as_usize())?;
 
-        let mut stateful: MemoryStateful<'static> = state.stateful_at(last_block.header.state_root);
+        let mut stateful: ChainStateful = state.stateful_at(last_block.header.state_root);
         let mut steps = Vec::new();
         for transaction in block.transactions.clone() {
-            let (mut local_steps, vm) = replay_transaction::<P>(&stateful, transaction,
+            let (mut local_steps, vm) = replay_transaction::<P>(&stateful, state.unsigned_senders(), transaction,
                                                                 &block, &last_hashes,
                                                                 &config)?;
             steps.append(&mut local_steps);
@@ -701,7 +775,6 @@ impl<P: 'static + Patch + Send> DebugRPC for MinerDebugRPC<P> {
     }
 
     fn trace_block_from_file(&self, path: String, config: Trailing<RPCTraceConfig>) -> Result<RPCBlockTrace, Error> {
-        use std::fs::File;
         use std::io::Read;
 
         let config = config.unwrap_or(RPCTraceConfig::default());
@@ -711,13 +784,13 @@ impl<P: 'static + Patch + Send> DebugRPC for MinerDebugRPC<P> {
 
         let state = self.state.lock().unwrap();
         let block: Block = UntrustedRlp::new(&buffer).as_val()?;
-        let last_block = state.get_block_by_number(if block.header.number == U256::zero() { 0 } else { block.header.number.as_usize() - 1 });
-        let last_hashes = state.get_last_256_block_hashes_by_number(block.header.number.as_usize());
+        let last_block = state.get_block_by_number(if block.header.number == U256::zero() { 0 } else { block.header.number.as_usize() - 1 })?;
+        let last_hashes = state.get_last_256_block_hashes_by_number(block.header.number.as_usize())?;
 
-        let mut stateful: MemoryStateful<'static> = state.stateful_at(last_block.header.state_root);
+        let mut stateful: ChainStateful = state.stateful_at(last_block.header.state_root);
         let mut steps = Vec::new();
         for transaction in block.transactions.clone() {
-            let (mut local_steps, vm) = replay_transaction::<P>(&stateful, transaction,
+            let (mut local_steps, vm) = replay_transaction::<P>(&stateful, state.unsigned_senders(), transaction,
                                                                 &block, &last_hashes,
                                                                 &config)?;
             steps.append(&mut local_steps);
@@ -735,35 +808,203 @@ impl<P: 'static + Patch + Send> DebugRPC for MinerDebugRPC<P> {
 
     fn dump_block(&self, number: usize) -> Result<RPCDump, Error> {
         let state = self.state.lock().unwrap();
-        let block: Block = state.get_block_by_number(number);
 
-        let mut accounts = HashMap::new();
-        let database = state.stateful().database();
-        let trie: FixedSecureTrie<_, Address, Account> = database.create_fixed_secure_trie(block.header.state_root);
-        let code_hashes = database.create_guard();
+        to_rpc_dump(&state, number)
+    }
+}
 
-        for (address, storage) in state.dump_accounts(number) {
-            let mut rpc_storage = HashMap::new();
-            for (key, value) in storage {
-                rpc_storage.insert(Hex(key), Hex(value));
-            }
+impl<P: 'static + Patch + Send> EvmRPC for MinerEvmRPC<P> {
+    fn mine(&self, timestamp: Trailing<u64>) -> Result<String, Error> {
+        let mut state = self.state.lock().unwrap();
 
-            let account = trie.get(&address).unwrap();
-            let code = code_hashes.get(account.code_hash).unwrap();
-
-            accounts.insert(Hex(address), RPCDumpAccount {
-                balance: Hex(account.balance),
-                code: Bytes(code),
-                code_hash: Hex(account.code_hash),
-                nonce: Hex(account.nonce),
-                root: Hex(account.storage_root),
-                storage: rpc_storage,
-            });
+        let timestamp: Option<u64> = timestamp.into();
+        if let Some(timestamp) = timestamp {
+            state.set_next_timestamp(timestamp);
         }
+        miner::mine_one::<P>(&mut state);
+        Ok("0x0".to_string())
+    }
 
-        Ok(RPCDump {
-            accounts,
-            root: Hex(block.header.state_root)
-        })
+    fn increase_time(&self, seconds: u64) -> Result<i64, Error> {
+        let mut state = self.state.lock().unwrap();
+
+        Ok(state.increase_time(seconds))
+    }
+
+    fn set_next_block_timestamp(&self, timestamp: u64) -> Result<bool, Error> {
+        let mut state = self.state.lock().unwrap();
+
+        state.set_next_timestamp(timestamp);
+        Ok(true)
+    }
+
+    fn snapshot(&self) -> Result<Hex<usize>, Error> {
+        let mut state = self.state.lock().unwrap();
+
+        Ok(Hex(state.snapshot()))
+    }
+
+    fn revert(&self, id: Hex<U256>) -> Result<bool, Error> {
+        let mut state = self.state.lock().unwrap();
+
+        if id.0 > U256::from(usize::max_value()) {
+            return Ok(false);
+        }
+        Ok(state.revert(id.0.as_usize()))
+    }
+}
+
+impl<P: 'static + Patch + Send> PersonalRPC for MinerPersonalRPC<P> {
+    fn new_account(&self, password: String) -> Result<Hex<Address>, Error> {
+        let mut state = self.state.lock().unwrap();
+
+        let mut rng = OsRng::new().map_err(|err| Error::InternalError(format!("{}", err)))?;
+        let secret_key = SecretKey::new(&SECP256K1, &mut rng);
+        Ok(Hex(state.new_account(secret_key, &password)?))
+    }
+
+    fn import_raw_key(&self, key: String, password: String) -> Result<Hex<Address>, Error> {
+        let mut state = self.state.lock().unwrap();
+
+        // The key is hex, usually without the 0x prefix.
+        let key = read_hex(&format!("0x{}", key.trim_start_matches("0x")))?;
+        let secret_key = SecretKey::from_slice(&SECP256K1, &key)?;
+        Ok(Hex(state.new_account(secret_key, &password)?))
+    }
+
+    fn list_accounts(&self) -> Result<Vec<Hex<Address>>, Error> {
+        let state = self.state.lock().unwrap();
+
+        Ok(state.accounts().into_iter().map(|address| {
+            Hex(address)
+        }).collect())
+    }
+
+    fn unlock_account(&self, address: Hex<Address>, password: String, duration: Trailing<u64>) -> Result<bool, Error> {
+        let mut state = self.state.lock().unwrap();
+
+        // As in geth, the default is 300 seconds, and 0 means until locked.
+        let duration: Option<u64> = duration.into();
+        let duration = match duration {
+            Some(0) => None,
+            Some(duration) => Some(duration),
+            None => Some(300),
+        };
+        state.unlock_account(address.0, &password, duration)?;
+        Ok(true)
+    }
+
+    fn lock_account(&self, address: Hex<Address>) -> Result<bool, Error> {
+        let mut state = self.state.lock().unwrap();
+
+        Ok(state.lock_account(address.0))
+    }
+
+    fn send_transaction(&self, transaction: RPCTransaction, password: String) -> Result<Hex<H256>, Error> {
+        let mut state = self.state.lock().unwrap();
+
+        let address = match transaction.from {
+            Some(ref val) => val.0,
+            None => return Err(Error::NotFound),
+        };
+        let secret_key = state.decrypt_account(address, &password)?;
+        let transaction = to_signed_transaction_with_key(&state, transaction, &secret_key)?;
+        validate_transaction::<P>(&state, address, &transaction)?;
+
+        let hash = state.append_pending_transaction(transaction)?;
+        if state.mining_mode() == MiningMode::Automine {
+            miner::mine_transaction::<P>(&mut state, address, hash);
+        }
+        Ok(Hex(hash))
+    }
+
+    fn sign(&self, message: Bytes, address: Hex<Address>, password: String) -> Result<Bytes, Error> {
+        let state = self.state.lock().unwrap();
+
+        let secret_key = state.decrypt_account(address.0, &password)?;
+        Ok(Bytes(sign_hash(personal_message_hash(&message.0), &secret_key)?))
+    }
+
+    fn ec_recover(&self, message: Bytes, signature: Bytes) -> Result<Hex<Address>, Error> {
+        Ok(Hex(recover_hash(personal_message_hash(&message.0), &signature.0)?))
+    }
+}
+
+impl<P: 'static + Patch + Send> SvmRPC for MinerSvmRPC<P> {
+    fn mine(&self) -> Result<Hex<usize>, Error> {
+        let mut state = self.state.lock().unwrap();
+
+        miner::mine_one::<P>(&mut state);
+        Ok(Hex(state.block_height()))
+    }
+
+    fn mining_mode(&self) -> Result<String, Error> {
+        let state = self.state.lock().unwrap();
+
+        Ok(format!("{}", state.mining_mode()))
+    }
+
+    fn set_mining_mode(&self, mode: String) -> Result<bool, Error> {
+        let mode = MiningMode::from_str(&mode)?;
+        self.state.lock().unwrap().set_mining_mode(mode);
+        // Wake the mining loop so that it picks up the new mode.
+        self.channel.send(true)
+            .map_err(|_| Error::InternalError("mining loop has stopped".to_string()))?;
+        Ok(true)
+    }
+
+    fn transaction_failure(&self, hash: Hex<H256>) -> Result<Option<String>, Error> {
+        let state = self.state.lock().unwrap();
+
+        Ok(state.transaction_failure(hash.0))
+    }
+
+    fn export_state(&self, path: String, block: Trailing<String>) -> Result<bool, Error> {
+        let state = self.state.lock().unwrap();
+
+        let number = from_block_number(&state, block)?;
+        super::write_state_file(&state, number, Path::new(&path))?;
+        Ok(true)
+    }
+
+    fn set_balance(&self, address: Hex<Address>, balance: Hex<U256>) -> Result<bool, Error> {
+        let mut state = self.state.lock().unwrap();
+
+        state.set_balance(address.0, balance.0)?;
+        Ok(true)
+    }
+
+    fn set_code(&self, address: Hex<Address>, code: Bytes) -> Result<bool, Error> {
+        let mut state = self.state.lock().unwrap();
+
+        state.set_code(address.0, code.0)?;
+        Ok(true)
+    }
+
+    fn set_nonce(&self, address: Hex<Address>, nonce: Hex<U256>) -> Result<bool, Error> {
+        let mut state = self.state.lock().unwrap();
+
+        state.set_nonce(address.0, nonce.0)?;
+        Ok(true)
+    }
+
+    fn set_storage_at(&self, address: Hex<Address>, index: Hex<U256>, value: Hex<U256>) -> Result<bool, Error> {
+        let mut state = self.state.lock().unwrap();
+
+        state.set_storage_at(address.0, index.0, M256::from(value.0))?;
+        Ok(true)
+    }
+
+    fn impersonate_account(&self, address: Hex<Address>) -> Result<bool, Error> {
+        let mut state = self.state.lock().unwrap();
+
+        state.impersonate_account(address.0);
+        Ok(true)
+    }
+
+    fn stop_impersonating_account(&self, address: Hex<Address>) -> Result<bool, Error> {
+        let mut state = self.state.lock().unwrap();
+
+        Ok(state.stop_impersonating_account(address.0))
     }
 }
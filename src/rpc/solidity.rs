@@ -63,7 +63,7 @@ pub fn parse_source(s: &str) -> Result<Vec<SourceItem>, Error> {
         }
 
         if file_name.is_none() {
-            return Err(Error::InvalidParams);
+            return Err(Error::InvalidParams("source map item without a file".to_string()));
         }
 
         ret.push(SourceItem { offset: values[0], length: values[1], file_name: file_name.unwrap() });
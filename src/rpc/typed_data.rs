@@ -0,0 +1,248 @@
+use bigint::{H256, U256};
+use hexutil::read_hex;
+use serde_json::{self, Value};
+use sha3::{Digest, Keccak256};
+use std::collections::{HashMap, BTreeSet};
+use std::str::FromStr;
+
+use error::Error;
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct TypedDataField {
+    pub name: String,
+    #[serde(rename = "type")]
+    pub field_type: String,
+}
+
+/// EIP-712 typed data, as passed to `eth_signTypedData`. Encoding follows
+/// the v4 rules, so that arrays and nested structs are supported.
+#[derive(Deserialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct TypedData {
+    pub types: HashMap<String, Vec<TypedDataField>>,
+    pub primary_type: String,
+    pub domain: Value,
+    pub message: Value,
+}
+
+fn keccak(data: &[u8]) -> H256 {
+    H256::from(Keccak256::digest(data).as_slice())
+}
+
+/// Element type of an array type, such as `Person` for `Person[]` or
+/// `Person[2]`.
+fn array_item(field_type: &str) -> Option<&str> {
+    if field_type.ends_with(']') {
+        field_type.rfind('[').map(|index| &field_type[..index])
+    } else {
+        None
+    }
+}
+
+/// Read an integer given either as a JSON number or as a decimal or
+/// 0x-prefixed hex string. Negative values are returned in two's
+/// complement.
+fn read_integer(value: &Value, signed: bool) -> Result<U256, Error> {
+    let (negative, magnitude) = match *value {
+        Value::Number(ref number) => match (number.as_u64(), number.as_i64()) {
+            (Some(value), _) => (false, U256::from(value)),
+            (None, Some(value)) => (true, U256::from(value.wrapping_neg() as u64)),
+            _ => return Err(invalid_value("integer", value)),
+        },
+        Value::String(ref s) => {
+            let (negative, s) = if s.starts_with('-') {
+                (true, &s[1..])
+            } else {
+                (false, &s[..])
+            };
+            let magnitude = if s.starts_with("0x") {
+                U256::from_str(s).map_err(|_| Error::HexError(s.to_string()))?
+            } else {
+                U256::from_dec_str(s).map_err(|_| Error::IntError(s.to_string()))?
+            };
+            (negative, magnitude)
+        },
+        _ => return Err(invalid_value("integer", value)),
+    };
+
+    match (negative, signed) {
+        (false, _) => Ok(magnitude),
+        (true, true) => Ok((!magnitude).overflowing_add(U256::one()).0),
+        (true, false) => Err(invalid_value("unsigned integer", value)),
+    }
+}
+
+fn read_bytes(value: &Value) -> Result<Vec<u8>, Error> {
+    Ok(read_hex(value.as_str().ok_or_else(|| invalid_value("bytes", value))?)?)
+}
+
+fn invalid_value(field_type: &str, value: &Value) -> Error {
+    Error::InvalidParams(format!("invalid {} value {}", field_type, value))
+}
+
+fn unknown_type(name: &str) -> Error {
+    Error::InvalidParams(format!("unknown type {}", name))
+}
+
+impl TypedData {
+    /// Parse the typed data, given either as a JSON object or as a string
+    /// holding one, as wallets accept both.
+    pub fn from_value(value: Value) -> Result<TypedData, Error> {
+        match value {
+            Value::String(s) => serde_json::from_str(&s),
+            value => serde_json::from_value(value),
+        }.map_err(|err| Error::InvalidParams(format!("typed data: {}", err)))
+    }
+
+    fn dependencies(&self, field_type: &str, found: &mut BTreeSet<String>) {
+        let mut field_type = field_type;
+        while let Some(item) = array_item(field_type) {
+            field_type = item;
+        }
+
+        if found.contains(field_type) {
+            return;
+        }
+        if let Some(fields) = self.types.get(field_type) {
+            found.insert(field_type.to_string());
+            for field in fields {
+                self.dependencies(&field.field_type, found);
+            }
+        }
+    }
+
+    /// Type encoding of a struct, followed by the struct types it refers
+    /// to in alphabetical order.
+    pub fn encode_type(&self, primary_type: &str) -> Result<String, Error> {
+        let mut dependencies = BTreeSet::new();
+        self.dependencies(primary_type, &mut dependencies);
+        dependencies.remove(primary_type);
+
+        let mut ret = String::new();
+        for name in Some(primary_type.to_string()).into_iter().chain(dependencies.into_iter()) {
+            let fields = self.types.get(&name).ok_or_else(|| unknown_type(&name))?;
+            let fields: Vec<String> = fields.iter().map(|field| {
+                format!("{} {}", field.field_type, field.name)
+            }).collect();
+            ret.push_str(&format!("{}({})", name, fields.join(",")));
+        }
+        Ok(ret)
+    }
+
+    pub fn hash_struct(&self, primary_type: &str, data: &Value) -> Result<H256, Error> {
+        let fields = self.types.get(primary_type).ok_or_else(|| unknown_type(primary_type))?;
+
+        let mut encoded = Vec::new();
+        encoded.extend_from_slice(&keccak(self.encode_type(primary_type)?.as_bytes()));
+        for field in fields {
+            let value = data.get(&field.name).unwrap_or(&Value::Null);
+            encoded.extend_from_slice(&self.encode_value(&field.field_type, value)?);
+        }
+        Ok(keccak(&encoded))
+    }
+
+    fn encode_value(&self, field_type: &str, value: &Value) -> Result<H256, Error> {
+        if self.types.contains_key(field_type) {
+            return match *value {
+                Value::Null => Ok(H256::default()),
+                _ => self.hash_struct(field_type, value),
+            };
+        }
+
+        if let Some(item) = array_item(field_type) {
+            let mut encoded = Vec::new();
+            for value in value.as_array().ok_or_else(|| invalid_value(field_type, value))? {
+                encoded.extend_from_slice(&self.encode_value(item, value)?);
+            }
+            return Ok(keccak(&encoded));
+        }
+
+        match field_type {
+            "string" => Ok(keccak(value.as_str().ok_or_else(|| invalid_value(field_type, value))?.as_bytes())),
+            "bytes" => Ok(keccak(&read_bytes(value)?)),
+            "bool" => {
+                let value = value.as_bool().ok_or_else(|| invalid_value(field_type, value))?;
+                Ok(H256::from(U256::from(if value { 1u64 } else { 0u64 })))
+            },
+            "address" => {
+                let bytes = read_bytes(value)?;
+                if bytes.len() != 20 {
+                    return Err(invalid_value(field_type, value));
+                }
+                let mut ret = [0u8; 32];
+                ret[12..].copy_from_slice(&bytes);
+                Ok(H256::from(&ret[..]))
+            },
+            _ if field_type.starts_with("bytes") => {
+                let bytes = read_bytes(value)?;
+                if bytes.len() > 32 {
+                    return Err(invalid_value(field_type, value));
+                }
+                let mut ret = [0u8; 32];
+                ret[..bytes.len()].copy_from_slice(&bytes);
+                Ok(H256::from(&ret[..]))
+            },
+            _ if field_type.starts_with("uint") => Ok(H256::from(read_integer(value, false)?)),
+            _ if field_type.starts_with("int") => Ok(H256::from(read_integer(value, true)?)),
+            _ => Err(unknown_type(field_type)),
+        }
+    }
+
+    /// The hash to be signed, `keccak256("\x19\x01" ‖ domainSeparator ‖
+    /// hashStruct(message))`.
+    pub fn signing_hash(&self) -> Result<H256, Error> {
+        let mut data = vec![0x19u8, 0x01];
+        data.extend_from_slice(&self.hash_struct("EIP712Domain", &self.domain)?);
+        if self.primary_type != "EIP712Domain" {
+            data.extend_from_slice(&self.hash_struct(&self.primary_type, &self.message)?);
+        }
+        Ok(keccak(&data))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn eip712_mail_example() {
+        let typed_data = TypedData::from_value(Value::String(r#"{
+            "types": {
+                "EIP712Domain": [
+                    { "name": "name", "type": "string" },
+                    { "name": "version", "type": "string" },
+                    { "name": "chainId", "type": "uint256" },
+                    { "name": "verifyingContract", "type": "address" }
+                ],
+                "Person": [
+                    { "name": "name", "type": "string" },
+                    { "name": "wallet", "type": "address" }
+                ],
+                "Mail": [
+                    { "name": "from", "type": "Person" },
+                    { "name": "to", "type": "Person" },
+                    { "name": "contents", "type": "string" }
+                ]
+            },
+            "primaryType": "Mail",
+            "domain": {
+                "name": "Ether Mail",
+                "version": "1",
+                "chainId": 1,
+                "verifyingContract": "0xCcCCccccCCCCcCCCCCCcCcCccCcCCCcCcccccccC"
+            },
+            "message": {
+                "from": { "name": "Cow", "wallet": "0xCD2a3d9F938E13CD947Ec05AbC7FE734Df8DD826" },
+                "to": { "name": "Bob", "wallet": "0xbBbBBBBbbBBBbbbBbbBbbbbBBbBbbbbBbBbbBBbB" },
+                "contents": "Hello, Bob!"
+            }
+        }"#.to_string())).unwrap();
+
+        assert_eq!(typed_data.encode_type("Mail").unwrap(),
+                   "Mail(Person from,Person to,string contents)Person(string name,address wallet)");
+        assert_eq!(typed_data.hash_struct("EIP712Domain", &typed_data.domain).unwrap(),
+                   H256::from_str("0xf2cee375fa42b42143804025fc449deafd50cc031ca257e0b194a650a912090f").unwrap());
+        assert_eq!(typed_data.signing_hash().unwrap(),
+                   H256::from_str("0xbe609aee343fb3c4b28e1df9e632fca64fcfaede20f02e86244efddf30957bd2").unwrap());
+    }
+}
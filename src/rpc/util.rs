@@ -1,21 +1,25 @@
-use super::{EthereumRPC, Either, RPCStep, RPCTransaction, RPCBlock, RPCLog, RPCReceipt, RPCTopicFilter, RPCLogFilter, RPCTraceConfig, RPCBreakpointConfig, RPCSourceMapConfig};
+use super::{Either, RPCAccountOverride, RPCBlockOverrides, RPCStep, RPCDump, RPCDumpAccount, RPCProof, RPCStorageProof, RPCTransaction, RPCBlock, RPCLog, RPCReceipt, RPCTopicFilter, RPCLogFilter, RPCTraceConfig, RPCBreakpointConfig, RPCSourceMapConfig};
 use super::filter::*;
 use super::serialize::*;
 use super::solidity::*;
-use error::Error;
-use miner::MinerState;
+use super::proof::{prove, empty_trie_root};
+use error::{Error, revert_reason};
+use miner::{self, MinerState, ChainStateful, Genesis, GenesisAccount, AccountInfo};
 
-use rlp::{self, UntrustedRlp};
-use bigint::{M256, U256, H256, H2048, Address, Gas};
+use rlp::{self, RlpStream};
+use bigint::{M256, U256, H256, H2048, B256, Address, Gas};
 use hexutil::{read_hex, to_hex};
-use block::{Block, TotalHeader, Account, Log, Receipt, FromKey, Transaction, UnsignedTransaction, TransactionAction, GlobalSignaturePatch, RlpHash};
+use block::{Block, TotalHeader, Account, Receipt, FromKey, Transaction, UnsignedTransaction, TransactionAction, TransactionSignature, RlpHash};
 use blockchain::chain::HeaderHash;
+use trie::{Database, FixedSecureTrie};
 use sputnikvm::{ValidTransaction, UntrustedTransaction, VM, VMStatus, MachineStatus, HeaderParams, SeqTransactionVM, Patch, Memory, AccountChange, AccountCommitment};
-use sputnikvm_stateful::MemoryStateful;
+use sputnikvm::errors::OnChainError;
 use std::str::FromStr;
 use std::collections::HashMap;
 use std::rc::Rc;
 use sha3::{Keccak256, Digest};
+use secp256k1::{SECP256K1, Message, RecoverableSignature, RecoveryId};
+use secp256k1::key::SecretKey;
 
 use jsonrpc_macros::Trailing;
 
@@ -25,11 +29,12 @@ pub fn from_block_number<T: Into<Option<String>>>(state: &MinerState, value: T)
     if value == Some("latest".to_string()) || value == Some("pending".to_string()) || value == None {
         Ok(state.block_height())
     } else if value == Some("earliest".to_string()) {
-        Ok(0)
+        Ok(state.genesis_number())
     } else {
-        let v: u64 = U256::from(read_hex(&value.unwrap())?.as_slice()).into();
+        let value = value.unwrap();
+        let v: u64 = U256::from(read_hex(&value).map_err(|_| Error::HexError(value.clone()))?.as_slice()).into();
         let v = v as usize;
-        if v > state.block_height() {
+        if v > state.block_height() || v < state.genesis_number() {
             Err(Error::NotFound)
         } else {
             Ok(v)
@@ -37,6 +42,27 @@ pub fn from_block_number<T: Into<Option<String>>>(state: &MinerState, value: T)
     }
 }
 
+/// Header parameters for `eth_call` and `eth_estimateGas`. Calls against
+/// the latest block see the chain clock instead of the block's timestamp.
+pub fn to_call_header_params(state: &MinerState, block: &Block) -> HeaderParams {
+    let mut header = HeaderParams::from(&block.header);
+    if block.header.number.as_usize() == state.block_height() {
+        header.timestamp = state.timestamp();
+    }
+    header
+}
+
+/// Parse a filter id, or return `None` if it is too large to be the id of
+/// an installed filter.
+pub fn to_filter_id(value: &str) -> Result<Option<usize>, Error> {
+    let id = U256::from_str(value)?;
+    if id > U256::from(usize::max_value()) {
+        Ok(None)
+    } else {
+        Ok(Some(id.as_usize()))
+    }
+}
+
 pub fn to_rpc_log(receipt: &Receipt, index: usize, transaction: &Transaction, block: &Block) -> RPCLog {
     use sha3::{Keccak256, Digest};
 
@@ -122,15 +148,16 @@ pub fn to_rpc_receipt(state: &MinerState, receipt: Receipt, transaction: &Transa
         },
         root: Hex(receipt.state_root),
         status: if state.receipt_status(transaction.rlp_hash()) { 1 } else { 0 },
+        revert_reason: state.revert_output(transaction.rlp_hash()).and_then(revert_reason),
     })
 }
 
-pub fn to_rpc_transaction(transaction: Transaction, block: Option<&Block>) -> RPCTransaction {
+pub fn to_rpc_transaction(state: &MinerState, transaction: Transaction, block: Option<&Block>) -> RPCTransaction {
     use sha3::{Keccak256, Digest};
     let hash = H256::from(Keccak256::digest(&rlp::encode(&transaction).to_vec()).as_slice());
 
     RPCTransaction {
-        from: Some(Hex(transaction.caller().unwrap())),
+        from: Some(Hex(state.transaction_sender(&transaction).unwrap())),
         to: match transaction.action {
             TransactionAction::Call(address) => Some(Hex(address)),
             TransactionAction::Create => None,
@@ -169,7 +196,7 @@ pub fn to_rpc_transaction(transaction: Transaction, block: Option<&Block>) -> RP
     }
 }
 
-pub fn to_rpc_block(block: Block, total_header: TotalHeader, full_transactions: bool) -> RPCBlock {
+pub fn to_rpc_block(state: &MinerState, block: Block, total_header: TotalHeader, full_transactions: bool) -> RPCBlock {
     use sha3::{Keccak256, Digest};
     let logs_bloom: H2048 = block.header.logs_bloom.clone().into();
 
@@ -195,7 +222,7 @@ pub fn to_rpc_block(block: Block, total_header: TotalHeader, full_transactions:
         gas_used: Hex(block.header.gas_used),
         timestamp: Hex(block.header.timestamp),
         transactions: if full_transactions {
-            Either::Right(block.transactions.iter().map(|t| to_rpc_transaction(t.clone(), Some(&block))).collect())
+            Either::Right(block.transactions.iter().map(|t| to_rpc_transaction(state, t.clone(), Some(&block))).collect())
         } else {
             Either::Left(block.transactions.iter().map(|t| {
                 let encoded = rlp::encode(t).to_vec();
@@ -206,34 +233,141 @@ pub fn to_rpc_block(block: Block, total_header: TotalHeader, full_transactions:
     }
 }
 
-pub fn to_signed_transaction(state: &MinerState, transaction: RPCTransaction, stateful: &MemoryStateful) -> Result<Transaction, Error> {
-    let address = match transaction.from {
-        Some(val) => val.0,
-        None => Address::default(),
+/// Account and storage proofs against the state root `root`. In fork mode
+/// they only cover the local state, so accounts and slots not yet fetched
+/// from upstream are proven absent.
+pub fn to_rpc_proof(state: &MinerState, root: H256, address: Address, keys: Vec<U256>) -> Result<RPCProof, Error> {
+    let stateful = state.stateful();
+    let account: Option<Account> = stateful.state_of(root).get(&address);
+    let storage_root = match account {
+        Some(ref account) => account.storage_root,
+        None => empty_trie_root(),
     };
-    let secret_key = {
-        let mut secret_key = None;
-        for key in state.accounts() {
-            if Address::from_secret_key(&key)? == address {
-                secret_key = Some(key);
-            }
+    let values: Vec<M256> = keys.iter().map(|key| {
+        match account {
+            Some(_) => stateful.storage_state_of(storage_root).get(&H256::from(*key)).unwrap_or(M256::zero()),
+            None => M256::zero(),
         }
-        match secret_key {
-            Some(val) => val,
-            None => return Err(Error::NotFound),
+    }).collect();
+
+    let database = stateful.database().create_guard();
+
+    let mut storage_proof = Vec::new();
+    for (key, value) in keys.into_iter().zip(values) {
+        storage_proof.push(RPCStorageProof {
+            key: Hex(key),
+            value: Hex(value),
+            proof: prove(&database, storage_root, &H256::from(key))?.into_iter().map(Bytes).collect(),
+        });
+    }
+
+    Ok(RPCProof {
+        address: Hex(address),
+        account_proof: prove(&database, root, &address)?.into_iter().map(Bytes).collect(),
+        balance: Hex(account.as_ref().map(|account| account.balance).unwrap_or(U256::zero())),
+        code_hash: Hex(match account {
+            Some(ref account) => account.code_hash,
+            None => H256::from(Keccak256::digest(b"").as_slice()),
+        }),
+        nonce: Hex(account.as_ref().map(|account| account.nonce).unwrap_or(U256::zero())),
+        storage_hash: Hex(storage_root),
+        storage_proof,
+    })
+}
+
+pub fn to_rpc_dump(state: &MinerState, number: usize) -> Result<RPCDump, Error> {
+    let root = state.state_root_at(number)?;
+
+    // Read every account before any code, so that the trie is dropped
+    // before the database is used again.
+    let mut dumped = Vec::new();
+    {
+        let database = state.stateful().database();
+        let trie: FixedSecureTrie<_, Address, Account> = database.create_fixed_secure_trie(root);
+        for (address, storage) in state.dump_accounts(number)? {
+            let account = trie.get(&address).ok_or(Error::NotFound)?;
+            dumped.push((address, account, storage));
+        }
+    }
+
+    let mut accounts = HashMap::new();
+    for (address, account, storage) in dumped {
+        let mut rpc_storage = HashMap::new();
+        for (key, value) in storage {
+            rpc_storage.insert(Hex(key), Hex(value));
+        }
+        let code = state.stateful().code(account.code_hash).ok_or(Error::NotFound)?;
+
+        accounts.insert(Hex(address), RPCDumpAccount {
+            balance: Hex(account.balance),
+            code: Bytes(code),
+            code_hash: Hex(account.code_hash),
+            nonce: Hex(account.nonce),
+            root: Hex(account.storage_root),
+            storage: rpc_storage,
+        });
+    }
+
+    Ok(RPCDump {
+        accounts,
+        root: Hex(root)
+    })
+}
+
+/// Genesis recreating every account of a state dump. Header fields are
+/// left to their defaults.
+pub fn from_rpc_dump(dump: RPCDump) -> Genesis {
+    let mut accounts = Vec::new();
+
+    for (address, account) in dump.accounts {
+        let mut storage = HashMap::new();
+        for (key, value) in account.storage {
+            storage.insert(key.0, value.0);
         }
+
+        accounts.push(GenesisAccount {
+            address: address.0,
+            balance: account.balance.0,
+            nonce: account.nonce.0,
+            code: account.code.0,
+            storage,
+        });
+    }
+
+    Genesis {
+        timestamp: None,
+        gas_limit: None,
+        extra_data: B256::default(),
+        difficulty: U256::zero(),
+        accounts,
+    }
+}
+
+pub fn to_signed_transaction(state: &MinerState, transaction: RPCTransaction) -> Result<Transaction, Error> {
+    let address = match transaction.from {
+        Some(ref val) => val.0,
+        None => Address::default(),
     };
-    let block = state.get_block_by_number(state.block_height());
-    let trie = stateful.state_of(block.header.state_root);
+    let secret_key = state.secret_key(address)?;
+    to_signed_transaction_with_key(state, transaction, &secret_key)
+}
+
+/// Transaction from an impersonated account, which is left unsigned.
+pub fn to_impersonated_transaction(state: &MinerState, address: Address, transaction: RPCTransaction) -> Transaction {
+    miner::unsigned_transaction(to_unsigned_transaction(state, address, transaction))
+}
 
-    let account: Option<Account> = trie.get(&address);
+pub fn to_signed_transaction_with_key(state: &MinerState, transaction: RPCTransaction, secret_key: &SecretKey) -> Result<Transaction, Error> {
+    let address = Address::from_secret_key(secret_key)?;
+    let unsigned = to_unsigned_transaction(state, address, transaction);
+    sign_transaction(unsigned, secret_key, state.signing_chain_id())
+}
 
-    let unsigned = UnsignedTransaction {
+fn to_unsigned_transaction(state: &MinerState, address: Address, transaction: RPCTransaction) -> UnsignedTransaction {
+    UnsignedTransaction {
         nonce: match transaction.nonce {
             Some(val) => val.0,
-            None => {
-                account.as_ref().map(|account| account.nonce).unwrap_or(U256::zero())
-            }
+            None => state.pending_nonce(address),
         },
         gas_price: match transaction.gas_price {
             Some(val) => val.0,
@@ -255,33 +389,144 @@ pub fn to_signed_transaction(state: &MinerState, transaction: RPCTransaction, st
             Some(val) => val.0,
             None => Vec::new(),
         },
+    }
+}
+
+/// Sign a transaction, with EIP-155 replay protection if a chain id is
+/// given.
+pub fn sign_transaction(unsigned: UnsignedTransaction, secret_key: &SecretKey, chain_id: Option<u64>) -> Result<Transaction, Error> {
+    let mut stream = RlpStream::new_list(if chain_id.is_some() { 9 } else { 6 });
+    stream.append(&unsigned.nonce);
+    stream.append(&unsigned.gas_price);
+    stream.append(&unsigned.gas_limit);
+    stream.append(&unsigned.action);
+    stream.append(&unsigned.value);
+    stream.append(&unsigned.input);
+    if let Some(chain_id) = chain_id {
+        stream.append(&chain_id);
+        stream.append(&0u8);
+        stream.append(&0u8);
+    }
+    let hash = H256::from(Keccak256::digest(&stream.out()).as_slice());
+
+    let sign = SECP256K1.sign_recoverable(&Message::from_slice(&hash)?, secret_key)?;
+    let (rec, sign) = sign.serialize_compact(&SECP256K1);
+    let v = rec.to_i32() as u64 + match chain_id {
+        Some(chain_id) => chain_id * 2 + 35,
+        None => 27,
     };
-    let transaction = unsigned.sign::<GlobalSignaturePatch>(&secret_key);
 
-    Ok(transaction)
+    Ok(Transaction {
+        nonce: unsigned.nonce,
+        gas_price: unsigned.gas_price,
+        gas_limit: unsigned.gas_limit,
+        action: unsigned.action,
+        value: unsigned.value,
+        signature: TransactionSignature {
+            v,
+            r: H256::from(&sign[0..32]),
+            s: H256::from(&sign[32..64]),
+        },
+        input: unsigned.input,
+    })
+}
+
+/// Hash signed by `eth_sign` and `personal_sign`, that of an EIP-191
+/// message with the `\x19Ethereum Signed Message:\n` prefix and the
+/// decimal length.
+pub fn personal_message_hash(message: &[u8]) -> H256 {
+    let mut data = format!("\x19Ethereum Signed Message:\n{}", message.len()).into_bytes();
+    data.extend_from_slice(message);
+    H256::from(Keccak256::digest(&data).as_slice())
+}
+
+/// Sign a hash, returning `r`, `s` and then `v` as 27 or 28.
+pub fn sign_hash(hash: H256, secret_key: &SecretKey) -> Result<Vec<u8>, Error> {
+    let sign = SECP256K1.sign_recoverable(&Message::from_slice(&hash)?, secret_key)?;
+    let (rec, sign) = sign.serialize_compact(&SECP256K1);
+    let mut ret = Vec::new();
+    ret.extend(sign.as_ref());
+    ret.push(rec.to_i32() as u8 + 27);
+
+    Ok(ret)
+}
+
+/// Address of the signer of a hash, from a signature returned by
+/// `sign_hash`. A `v` of 0 or 1 is accepted as well.
+pub fn recover_hash(hash: H256, signature: &[u8]) -> Result<Address, Error> {
+    if signature.len() != 65 {
+        return Err(Error::InvalidParams(format!("signature must be 65 bytes, got {}", signature.len())));
+    }
+
+    let v = signature[64];
+    let rec = RecoveryId::from_i32(if v >= 27 { v - 27 } else { v } as i32)?;
+    let sign = RecoverableSignature::from_compact(&SECP256K1, &signature[0..64], rec)?;
+    let public_key = SECP256K1.recover(&Message::from_slice(&hash)?, &sign)?;
+
+    Ok(Address::from_public_key(&public_key))
 }
 
-pub fn to_valid_transaction<P: Patch>(state: &MinerState, transaction: RPCTransaction, stateful: &MemoryStateful) -> Result<ValidTransaction, Error> {
+/// Check a transaction before it enters the transaction pool. Unlike
+/// `Stateful::to_valid`, a nonce ahead of the account nonce is accepted so
+/// that the transaction can be queued.
+pub fn validate_transaction<P: Patch>(state: &MinerState, caller: Address, transaction: &Transaction) -> Result<(), Error> {
+    if transaction.gas_limit > state.block_gas_limit() {
+        return Err(Error::ExceedsBlockGasLimit);
+    }
+
+    // EIP-155 transactions must be for this chain, and are refused by
+    // patches that predate it.
+    let v = transaction.signature.v;
+    if v >= 35 && Some((v - 35) / 2) != state.signing_chain_id() {
+        return Err(Error::InvalidChainId);
+    }
+
+    let stateful = state.stateful();
+    let (balance, code) = match state.account_at(stateful.root(), state.block_height(), caller)? {
+        Some(account) => {
+            if transaction.nonce < account.nonce {
+                return Err(Error::NonceTooLow);
+            }
+            (account.balance, Rc::new(account.code))
+        },
+        None => (U256::zero(), Rc::new(Vec::new())),
+    };
+
+    let untrusted = UntrustedTransaction {
+        caller: AccountCommitment::Full {
+            address: caller,
+            nonce: transaction.nonce,
+            balance,
+            code,
+        },
+        gas_price: transaction.gas_price,
+        gas_limit: transaction.gas_limit,
+        action: transaction.action.clone(),
+        value: transaction.value,
+        input: Rc::new(transaction.input.clone()),
+    };
+    untrusted.to_valid::<P>()?;
+
+    Ok(())
+}
+
+/// Build a transaction for `eth_call` from the caller's account in the
+/// state at `root`, which is the state of block `number`.
+pub fn to_valid_transaction<P: Patch>(
+    state: &MinerState, root: H256, number: usize, transaction: RPCTransaction
+) -> Result<ValidTransaction, Error> {
     let address = match transaction.from {
         Some(val) => val.0,
         None => Address::default(),
     };
 
-    let block = state.get_block_by_number(state.block_height());
-    let trie = stateful.state_of(block.header.state_root);
-
-    let account: Option<Account> = trie.get(&address);
-    let commitment = match account {
+    let commitment = match state.account_at(root, number, address)? {
         Some(account) => {
-            let code = match stateful.code(account.code_hash) {
-                Some(code) => Rc::new(code),
-                None => return Err(Error::NotFound),
-            };
             AccountCommitment::Full {
                 address,
                 nonce: account.nonce,
                 balance: account.balance,
-                code: code,
+                code: Rc::new(account.code),
             }
         },
         None => {
@@ -318,6 +563,176 @@ pub fn to_valid_transaction<P: Patch>(state: &MinerState, transaction: RPCTransa
     Ok(valid)
 }
 
+/// Apply `eth_call` account overrides to a scratch copy of the state after
+/// block `number`. `state` replaces the whole storage of the account, while
+/// `stateDiff` only changes the given slots.
+pub fn apply_state_overrides(
+    state: &MinerState, number: usize, stateful: &mut ChainStateful,
+    overrides: HashMap<Hex<Address>, RPCAccountOverride>
+) -> Result<(), Error> {
+    let fork = state.fork();
+    let mut changes = Vec::new();
+
+    for (address, account_override) in overrides {
+        let address = address.0;
+        if account_override.state.is_some() && account_override.state_diff.is_some() {
+            return Err(Error::InvalidParams(format!("both state and stateDiff given for 0x{:x}", address)));
+        }
+        if let Some(ref fork) = fork {
+            fork.materialize(stateful, address, number)?;
+        }
+
+        let (exists, mut info) = match state.account_at(stateful.root(), number, address)? {
+            Some(info) => (true, info),
+            None => (false, AccountInfo {
+                nonce: U256::zero(),
+                balance: U256::zero(),
+                code: Vec::new(),
+            }),
+        };
+        let code = info.code.clone();
+        if let Some(balance) = account_override.balance {
+            info.balance = balance.0;
+        }
+        if let Some(nonce) = account_override.nonce {
+            info.nonce = nonce.0;
+        }
+        if let Some(code) = account_override.code {
+            info.code = code.0;
+        }
+
+        let mut storage = miner::empty_storage(address);
+        if exists && info.code == code && account_override.state.is_none() {
+            for (index, value) in account_override.state_diff.unwrap_or_default() {
+                storage.write(index.0, M256::from(value.0)).unwrap();
+            }
+
+            changes.push(AccountChange::Full {
+                nonce: info.nonce,
+                address,
+                balance: info.balance,
+                changing_storage: storage,
+                code: Rc::new(info.code),
+            });
+        } else {
+            // Create replaces the whole storage, so unless it is
+            // overridden, the current one is carried over.
+            let slots: Vec<(U256, M256)> = match account_override.state {
+                Some(slots) => slots.into_iter().map(|(index, value)| (index.0, M256::from(value.0))).collect(),
+                None => {
+                    let mut slots = state.account_storage(number, address)?;
+                    for (index, value) in account_override.state_diff.unwrap_or_default() {
+                        slots.insert(index.0, M256::from(value.0));
+                    }
+                    slots.into_iter().collect()
+                },
+            };
+            for (index, value) in slots {
+                storage.write(index, value).unwrap();
+            }
+
+            changes.push(AccountChange::Create {
+                nonce: info.nonce,
+                address,
+                balance: info.balance,
+                storage,
+                code: Rc::new(info.code),
+            });
+        }
+    }
+
+    stateful.transit(&changes);
+    Ok(())
+}
+
+pub fn apply_block_overrides(header: &mut HeaderParams, overrides: RPCBlockOverrides) {
+    if let Some(number) = overrides.number {
+        header.number = number.0;
+    }
+    if let Some(time) = overrides.time {
+        header.timestamp = time.0.as_u64();
+    }
+    if let Some(coinbase) = overrides.coinbase {
+        header.beneficiary = coinbase.0;
+    }
+}
+
+/// Output of an executed call. A REVERT is returned as `Reverted` with
+/// its output, and any other failure as `ExecutionFailed`.
+pub fn call_output<P: Patch>(vm: &SeqTransactionVM<P>) -> Result<Vec<u8>, Error> {
+    match vm.status() {
+        VMStatus::ExitedOk => Ok(vm.out().into()),
+        VMStatus::ExitedErr(OnChainError::Revert) => Err(Error::Reverted(vm.out().into())),
+        VMStatus::ExitedErr(OnChainError::EmptyGas) => Err(Error::ExecutionFailed("out of gas".to_string())),
+        VMStatus::ExitedErr(err) => Err(Error::ExecutionFailed(format!("{:?}", err))),
+        VMStatus::ExitedNotSupported(err) => Err(Error::ExecutionFailed(format!("{:?}", err))),
+        VMStatus::Running => Err(Error::ExecutionFailed("not finished".to_string())),
+    }
+}
+
+/// Find the lowest gas limit at which the transaction executes without
+/// error, by binary search between its intrinsic gas and `cap`. Each
+/// attempt runs on a fresh copy of the state at `root`.
+pub fn estimate_gas<P: Patch>(
+    state: &MinerState, root: H256, valid: ValidTransaction, cap: Gas,
+    header: &HeaderParams, block_hashes: &[H256]
+) -> Result<Gas, Error> {
+    let fork = state.fork();
+    let run = |gas_limit: Gas| -> SeqTransactionVM<P> {
+        let mut transaction = valid.clone();
+        transaction.gas_limit = gas_limit;
+        let mut stateful = state.stateful_at(root);
+        miner::call(&mut stateful, &fork, transaction, header, block_hashes)
+    };
+    let succeeds = |vm: &SeqTransactionVM<P>| match vm.status() {
+        VMStatus::ExitedOk => true,
+        _ => false,
+    };
+
+    // The caller must be able to pay for the gas at its price.
+    let mut cap = cap;
+    if valid.gas_price > Gas::zero() {
+        let balance = match valid.caller {
+            Some(caller) => state.account_at(root, header.number.as_usize(), caller)?.map(|account| account.balance)
+                .unwrap_or(U256::zero()),
+            None => U256::zero(),
+        };
+        let available = if balance > valid.value { balance - valid.value } else { U256::zero() };
+        let gas_price: U256 = valid.gas_price.into();
+        let allowance = Gas::from(available / gas_price);
+        if allowance < cap {
+            cap = allowance;
+        }
+    }
+
+    let intrinsic_gas = valid.intrinsic_gas::<P>();
+    if cap < intrinsic_gas {
+        return Err(Error::GasRequiredExceedsAllowance(cap));
+    }
+
+    // Running out of gas at the cap means the cap is too low. Any other
+    // failure is returned as is.
+    let vm = run(cap);
+    match vm.status() {
+        VMStatus::ExitedErr(OnChainError::EmptyGas) => return Err(Error::GasRequiredExceedsAllowance(cap)),
+        _ => { call_output(&vm)?; },
+    }
+
+    // `lo` always fails and `hi` always succeeds.
+    let mut lo = intrinsic_gas - Gas::from(1u64);
+    let mut hi = cap;
+    while lo + Gas::from(1u64) < hi {
+        let mid = lo + (hi - lo) / Gas::from(2u64);
+        if succeeds(&run(mid)) {
+            hi = mid;
+        } else {
+            lo = mid;
+        }
+    }
+
+    Ok(hi)
+}
+
 pub fn from_topic_filter(filter: Option<RPCTopicFilter>) -> Result<TopicFilter, Error> {
     Ok(match filter {
         None => TopicFilter::All,
@@ -356,10 +771,10 @@ pub fn from_log_filter(state: &MinerState, filter: RPCLogFilter) -> Result<LogFi
 }
 
 pub fn replay_transaction<P: Patch>(
-    stateful: &MemoryStateful<'static>, transaction: Transaction, block: &Block,
+    stateful: &ChainStateful, unsigned_senders: &HashMap<H256, Address>, transaction: Transaction, block: &Block,
     last_hashes: &[H256], config: &RPCTraceConfig
 ) -> Result<(Vec<RPCStep>, SeqTransactionVM<P>), Error> {
-    let valid = stateful.to_valid::<P>(transaction)?;
+    let valid = miner::to_valid::<P>(stateful, unsigned_senders, transaction)?;
     let mut vm = SeqTransactionVM::<P>::new(valid, HeaderParams::from(&block.header));
     let mut steps = Vec::new();
     let mut last_gas = Gas::zero();
@@ -493,3 +908,25 @@ pub fn replay_transaction<P: Patch>(
 
     Ok((steps, vm))
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn personal_message_hash_matches_eip191() {
+        assert_eq!(personal_message_hash(b"Hello World"),
+                   H256::from_str("0xa1de988600a42c4b4ab089b619297c17d53cffae5d5120d82d8a92d0bb3b78f2").unwrap());
+    }
+
+    #[test]
+    fn sign_then_recover() {
+        let secret_key = SecretKey::from_slice(&SECP256K1, &[1u8; 32]).unwrap();
+        let hash = personal_message_hash(b"Hello World");
+        let signature = sign_hash(hash, &secret_key).unwrap();
+
+        assert_eq!(signature.len(), 65);
+        assert!(signature[64] == 27 || signature[64] == 28);
+        assert_eq!(recover_hash(hash, &signature).unwrap(), Address::from_secret_key(&secret_key).unwrap());
+    }
+}
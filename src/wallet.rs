@@ -0,0 +1,118 @@
+use bip39::{Mnemonic, MnemonicType, Language, Seed};
+use hmac::{Hmac, Mac};
+use sha2::Sha512;
+use secp256k1::SECP256K1;
+use secp256k1::key::{PublicKey, SecretKey};
+
+use error::Error;
+
+const HARDENED: u32 = 0x80000000;
+
+/// A new random 12-word mnemonic.
+pub fn generate_mnemonic() -> String {
+    Mnemonic::new(MnemonicType::Words12, Language::English).phrase().to_string()
+}
+
+/// SLIP-44 derivation path of the accounts for a chain, without the final
+/// address index.
+pub fn default_derivation_path(chain: Option<&str>) -> &'static str {
+    match chain.and_then(|chain| chain.split('-').next()) {
+        None | Some("classic") => "m/44'/61'/0'/0",
+        Some("morden") => "m/44'/1'/0'/0",
+        Some("foundation") => "m/44'/60'/0'/0",
+        Some("ellaism") => "m/44'/163'/0'/0",
+        Some("expanse") => "m/44'/40'/0'/0",
+        Some("musicoin") => "m/44'/184'/0'/0",
+        Some("ubiq") => "m/44'/108'/0'/0",
+        Some(_) => "m/44'/60'/0'/0",
+    }
+}
+
+fn parse_path(path: &str) -> Result<Vec<u32>, Error> {
+    let mut parts = path.split('/');
+    if parts.next() != Some("m") {
+        return Err(Error::InvalidParams(format!("derivation path {} does not start with m", path)));
+    }
+
+    let mut indexes = Vec::new();
+    for part in parts {
+        let index = if part.ends_with('\'') {
+            part[..(part.len() - 1)].parse::<u32>()? | HARDENED
+        } else {
+            part.parse::<u32>()?
+        };
+        indexes.push(index);
+    }
+    Ok(indexes)
+}
+
+fn hmac_sha512(key: &[u8], data: &[u8]) -> [u8; 64] {
+    let mut mac = Hmac::<Sha512>::new_varkey(key).unwrap();
+    mac.input(data);
+
+    let mut ret = [0u8; 64];
+    ret.copy_from_slice(mac.result().code().as_slice());
+    ret
+}
+
+/// BIP-32 private child key derivation.
+fn derive_child(secret_key: &SecretKey, chain_code: &[u8], index: u32) -> Result<(SecretKey, Vec<u8>), Error> {
+    let mut data = Vec::new();
+    if index & HARDENED != 0 {
+        data.push(0u8);
+        data.extend_from_slice(&secret_key[..]);
+    } else {
+        let public_key = PublicKey::from_secret_key(&SECP256K1, secret_key)?;
+        data.extend_from_slice(&public_key.serialize_vec(&SECP256K1, true));
+    }
+    data.extend_from_slice(&[(index >> 24) as u8, (index >> 16) as u8, (index >> 8) as u8, index as u8]);
+
+    let hash = hmac_sha512(chain_code, &data);
+    let mut child = SecretKey::from_slice(&SECP256K1, &hash[0..32])?;
+    child.add_assign(&SECP256K1, secret_key)?;
+
+    Ok((child, hash[32..64].to_vec()))
+}
+
+/// Derive `count` account keys from a BIP-39 mnemonic, at the given BIP-32
+/// path followed by the indexes `0` to `count - 1`.
+pub fn derive_keys(phrase: &str, path: &str, count: usize) -> Result<Vec<SecretKey>, Error> {
+    let mnemonic = Mnemonic::from_phrase(phrase, Language::English)
+        .map_err(|err| Error::InvalidParams(format!("mnemonic: {}", err)))?;
+    let seed = Seed::new(&mnemonic, "");
+
+    let master = hmac_sha512(b"Bitcoin seed", seed.as_bytes());
+    let mut secret_key = SecretKey::from_slice(&SECP256K1, &master[0..32])?;
+    let mut chain_code = master[32..64].to_vec();
+
+    for index in parse_path(path)? {
+        let (child, child_chain_code) = derive_child(&secret_key, &chain_code, index)?;
+        secret_key = child;
+        chain_code = child_chain_code;
+    }
+
+    let mut keys = Vec::new();
+    for index in 0..count {
+        let (child, _) = derive_child(&secret_key, &chain_code, index as u32)?;
+        keys.push(child);
+    }
+    Ok(keys)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bigint::Address;
+    use block::FromKey;
+    use std::str::FromStr;
+
+    #[test]
+    fn derive_first_foundation_account() {
+        let keys = derive_keys(
+            "myth like bonus scare over problem client lizard pioneer submit female collect",
+            "m/44'/60'/0'/0", 1).unwrap();
+
+        assert_eq!(Address::from_secret_key(&keys[0]).unwrap(),
+                   Address::from_str("0x90f8bf6a479f320ead074411a4b0e7944ea8c9c1").unwrap());
+    }
+}